@@ -0,0 +1,101 @@
+//! Measures hashing throughput and download concurrency against a local mock server.
+//!
+//! Run with `cargo bench -p abbs-update-checksum-core`.
+
+use std::time::Instant;
+
+use abbs_update_checksum_core::{update_from_sources, Source, UpdateChecksumOptions};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const CHUNK: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// Answers `GET /<id>/<size>` with `size` zero bytes.
+async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buf = vec![];
+    let mut tmp = [0; 1024];
+
+    while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = stream.read(&mut tmp).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&tmp[..n]);
+    }
+
+    let req = String::from_utf8_lossy(&buf);
+    let size = req
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| x.rsplit('/').next())
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    stream
+        .write_all(
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {size}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await?;
+
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(CHUNK.len());
+        stream.write_all(&CHUNK[..n]).await?;
+        left -= n;
+    }
+
+    stream.shutdown().await
+}
+
+async fn run(base: &str, name: &str, count: usize, size: usize, threads: usize) {
+    let sources = (0..count)
+        .map(|i| Source {
+            typ: "tbl".to_string(),
+            options: vec![],
+            url: format!("{base}/{i}/{size}"),
+            arch: None,
+        })
+        .collect::<Vec<_>>();
+
+    let opts = UpdateChecksumOptions {
+        threads,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    update_from_sources(&sources, |_| {}, &opts).await.unwrap();
+    let secs = start.elapsed().as_secs_f64();
+
+    println!(
+        "{name}: {count} x {size} bytes, {threads} threads: {:.1} MB/s, {:.1} requests/s",
+        (count * size) as f64 / secs / 1e6,
+        count as f64 / secs
+    );
+}
+
+fn main() {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(serve(stream));
+                }
+            });
+
+            run(&base, "hashing", 1, 512 << 20, 1).await;
+
+            for threads in [1, 4, 16] {
+                run(&base, "concurrency", 256, 64 << 10, threads).await;
+            }
+        });
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::debug;
+use reqwest::header::HeaderMap;
+use reqwest::header::CACHE_CONTROL;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::ChecksumAlgo;
+use crate::Hashed;
+
+/// Checksums of the URLs hashed in a run, reused for every later occurrence of
+/// the same URL unless its `Cache-Control` forbids it or its `max-age` expired.
+///
+/// Concurrent downloads of a URL wait for the first one to finish instead of
+/// fetching it too. Clones share the same entries.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<HashMap<(String, ChecksumAlgo), SharedEntry>>>,
+}
+
+/// Locked during the download of its URL.
+type SharedEntry = Arc<AsyncMutex<Option<Entry>>>;
+
+#[derive(Debug)]
+struct Entry {
+    hashed: Hashed,
+    /// `None` for responses reusable for the rest of the run.
+    expires: Option<Instant>,
+}
+
+/// Right to download a URL missing from a [`ResponseCache`], held until the
+/// result is recorded with [`Self::insert`], or the download fails.
+pub(crate) struct Slot(OwnedMutexGuard<Option<Entry>>);
+
+impl ResponseCache {
+    /// Returns the checksum of `url` if it can be reused, after waiting for its
+    /// download in progress if any, or the slot to record it in once downloaded.
+    pub(crate) async fn get(&self, url: &str, algo: ChecksumAlgo) -> Result<Hashed, Slot> {
+        let entry = self
+            .entries
+            .lock()
+            .unwrap()
+            .entry((url.to_string(), algo))
+            .or_default()
+            .clone();
+        let entry = entry.lock_owned().await;
+
+        match &*entry {
+            Some(x) if x.expires.is_some_and(|x| x <= Instant::now()) => {
+                debug!("Cached checksum of {url} expired");
+            }
+            Some(x) => {
+                debug!("Reusing cached checksum of {url}");
+                return Ok(x.hashed.clone());
+            }
+            None => {}
+        }
+
+        Err(Slot(entry))
+    }
+}
+
+impl Slot {
+    /// Records `hashed`, computed from the responses with `headers`, as allowed
+    /// by the most restrictive of them.
+    pub(crate) fn insert(mut self, url: &str, headers: &[HeaderMap], hashed: &Hashed) {
+        let mut expires = None;
+        for lifetime in headers.iter().filter_map(Lifetime::from_headers) {
+            match lifetime {
+                Lifetime::Immutable => {}
+                Lifetime::MaxAge(age) => {
+                    let at = Instant::now() + age;
+                    expires = Some(expires.map_or(at, |x: Instant| x.min(at)));
+                }
+                Lifetime::NoReuse => {
+                    debug!("Not caching checksum of {url}: forbidden by Cache-Control");
+                    *self.0 = None;
+                    return;
+                }
+            }
+        }
+
+        match expires {
+            Some(x) => debug!(
+                "Caching checksum of {url} for {}s",
+                (x - Instant::now()).as_secs()
+            ),
+            None => debug!("Caching checksum of {url} for the rest of the run"),
+        }
+        *self.0 = Some(Entry {
+            hashed: hashed.clone(),
+            expires,
+        });
+    }
+}
+
+/// How long a response may be reused according to its `Cache-Control` header.
+#[derive(Debug, PartialEq, Eq)]
+enum Lifetime {
+    Immutable,
+    MaxAge(Duration),
+    NoReuse,
+}
+
+impl Lifetime {
+    /// Returns `None` without a directive about reuse.
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(CACHE_CONTROL)?.to_str().ok()?;
+
+        let mut immutable = false;
+        let mut max_age = None;
+        for directive in value.split(',') {
+            let directive = directive.trim().to_lowercase();
+
+            match directive.split_once('=') {
+                Some(("max-age", v)) => max_age = v.trim_matches('"').parse::<u64>().ok(),
+                None if directive == "immutable" => immutable = true,
+                None if directive == "no-store" || directive == "no-cache" => {
+                    return Some(Self::NoReuse)
+                }
+                _ => {}
+            }
+        }
+
+        match max_age {
+            _ if immutable => Some(Self::Immutable),
+            Some(0) => Some(Self::NoReuse),
+            Some(x) => Some(Self::MaxAge(Duration::from_secs(x))),
+            None => None,
+        }
+    }
+}
+
+#[test]
+fn test_cache_lifetime() {
+    let lifetime = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, value.parse().unwrap());
+        Lifetime::from_headers(&headers)
+    };
+
+    assert_eq!(
+        lifetime("public, max-age=31536000, immutable"),
+        Some(Lifetime::Immutable)
+    );
+    assert_eq!(
+        lifetime("max-age=600"),
+        Some(Lifetime::MaxAge(Duration::from_secs(600)))
+    );
+    assert_eq!(lifetime("max-age=0"), Some(Lifetime::NoReuse));
+    assert_eq!(lifetime("no-store, max-age=600"), Some(Lifetime::NoReuse));
+    assert_eq!(lifetime("public"), None);
+    assert_eq!(Lifetime::from_headers(&HeaderMap::new()), None);
+}
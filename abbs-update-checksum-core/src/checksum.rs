@@ -0,0 +1,258 @@
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use blake2::Blake2b512;
+use eyre::bail;
+use faster_hex::hex_string;
+use sha2::Digest;
+use sha2::Sha256;
+use sha2::Sha512;
+
+/// Hash algorithms checksums can be computed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+    /// BLAKE2b with a 512-bit digest.
+    Blake2b,
+}
+
+impl ChecksumAlgo {
+    /// Every supported algorithm.
+    pub const ALL: [Self; 3] = [Self::Sha256, Self::Sha512, Self::Blake2b];
+
+    /// Returns the name used as checksum prefix, e.g. `sha256` in `sha256::<hex>`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake2b => "blake2b",
+        }
+    }
+
+    /// Returns the length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha512 | Self::Blake2b => 64,
+        }
+    }
+
+    pub(crate) fn hasher(&self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(Sha512::new()),
+            Self::Blake2b => Hasher::Blake2b(Blake2b512::new()),
+        }
+    }
+}
+
+impl Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "blake2b" => Ok(Self::Blake2b),
+            _ => bail!("Unsupported checksum algorithm: {s}"),
+        }
+    }
+}
+
+/// Streaming digest state of an algorithm, built in or supplied with a
+/// [`CustomHasher`].
+pub trait ChecksumHasher: Send {
+    fn update(&mut self, data: &[u8]);
+    /// Returns the digest of the data fed so far, in lowercase hex.
+    fn finalize_hex(self: Box<Self>) -> String;
+    /// Returns the name of the algorithm, written as prefix of the checksums, e.g.
+    /// `sha256` in `sha256::<hex>`.
+    fn prefix(&self) -> &str;
+}
+
+/// Returns the checksum of what was fed to `hasher`, in `<algo>::<hex>` form.
+pub(crate) fn finalize(hasher: Box<dyn ChecksumHasher>) -> String {
+    let prefix = hasher.prefix().to_string();
+
+    format!("{prefix}::{}", hasher.finalize_hex())
+}
+
+/// Builds the [`ChecksumHasher`] every source is hashed with instead of one of
+/// the [`ChecksumAlgo`], e.g. of an organization-specific digest.
+///
+/// Clones share the same function.
+#[derive(Clone)]
+pub struct CustomHasher(Arc<dyn Fn() -> Box<dyn ChecksumHasher> + Send + Sync>);
+
+impl CustomHasher {
+    pub fn new(f: impl Fn() -> Box<dyn ChecksumHasher> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn build(&self) -> Box<dyn ChecksumHasher> {
+        (self.0)()
+    }
+}
+
+impl Debug for CustomHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomHasher")
+    }
+}
+
+/// Streaming digest state of a [`ChecksumAlgo`].
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake2b(Blake2b512),
+}
+
+impl ChecksumHasher for Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+            Self::Blake2b(h) => h.update(data),
+        }
+    }
+
+    fn finalize_hex(self: Box<Self>) -> String {
+        match *self {
+            Self::Sha256(h) => hex_string(&h.finalize()),
+            Self::Sha512(h) => hex_string(&h.finalize()),
+            Self::Blake2b(h) => hex_string(&h.finalize()),
+        }
+    }
+
+    fn prefix(&self) -> &str {
+        match self {
+            Self::Sha256(_) => ChecksumAlgo::Sha256.name(),
+            Self::Sha512(_) => ChecksumAlgo::Sha512.name(),
+            Self::Blake2b(_) => ChecksumAlgo::Blake2b.name(),
+        }
+    }
+}
+
+/// Parses a `<algo>::<hex>` checksum, returning `None` for `SKIP` and malformed
+/// checksums, e.g. with a digest of the wrong length.
+pub fn parse_checksum(s: &str) -> Option<(ChecksumAlgo, Vec<u8>)> {
+    let (algo, hex) = s.split_once("::")?;
+    let algo = algo.parse::<ChecksumAlgo>().ok()?;
+
+    if hex.len() != algo.digest_len() * 2 {
+        return None;
+    }
+
+    let mut digest = vec![0; algo.digest_len()];
+    faster_hex::hex_decode(hex.as_bytes(), &mut digest).ok()?;
+
+    Some((algo, digest))
+}
+
+/// Formats a digest as a `<algo>::<hex>` checksum, the inverse of [`parse_checksum`].
+pub fn format_checksum(algo: ChecksumAlgo, digest: &[u8]) -> String {
+    format!("{algo}::{}", hex_string(digest))
+}
+
+/// Formats a `<algo>::<hex>` checksum as a Subresource Integrity digest, e.g.
+/// `sha256-<base64>`, as consumed by tools outside of ABBS. Returns `None` for
+/// `SKIP`, malformed checksums and BLAKE2b, which SRI does not define.
+pub fn format_sri(checksum: &str) -> Option<String> {
+    let (algo, digest) = parse_checksum(checksum)?;
+    if algo == ChecksumAlgo::Blake2b {
+        return None;
+    }
+
+    Some(format!("{algo}-{}", STANDARD.encode(digest)))
+}
+
+/// Sets the case of the algorithm name of a `<algo>::<hex>` checksum, e.g.
+/// `SHA256::<hex>` when `uppercase`.
+pub(crate) fn with_algo_case(checksum: &str, uppercase: bool) -> String {
+    match checksum.split_once("::") {
+        Some((algo, hex)) if uppercase => format!("{}::{hex}", algo.to_uppercase()),
+        Some((algo, hex)) => format!("{}::{hex}", algo.to_lowercase()),
+        None => checksum.to_string(),
+    }
+}
+
+#[test]
+fn test_with_algo_case() {
+    assert_eq!(with_algo_case("sha256::abc", true), "SHA256::abc");
+    assert_eq!(with_algo_case("SHA256::abc", false), "sha256::abc");
+    assert_eq!(with_algo_case("SKIP", true), "SKIP");
+}
+
+#[test]
+fn test_parse_checksum() {
+    let hex = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    let (algo, digest) = parse_checksum(&format!("sha256::{hex}")).unwrap();
+    assert_eq!(algo, ChecksumAlgo::Sha256);
+    assert_eq!(digest.len(), 32);
+    assert_eq!(format_checksum(algo, &digest), format!("sha256::{hex}"));
+
+    assert_eq!(
+        parse_checksum(&format!("SHA256::{hex}")).map(|x| x.0),
+        Some(ChecksumAlgo::Sha256)
+    );
+    assert_eq!(
+        parse_checksum(&format!("sha512::{hex}{hex}")).map(|x| x.0),
+        Some(ChecksumAlgo::Sha512)
+    );
+
+    assert_eq!(parse_checksum("SKIP"), None);
+    assert_eq!(parse_checksum(""), None);
+    assert_eq!(parse_checksum("sha256::"), None);
+    assert_eq!(parse_checksum(&format!("md5::{hex}")), None);
+    assert_eq!(parse_checksum(&format!("sha512::{hex}")), None);
+    assert_eq!(parse_checksum(&format!("sha256::{}", &hex[1..])), None);
+    assert_eq!(parse_checksum(&format!("sha256::{}g", &hex[1..])), None);
+    assert_eq!(
+        parse_checksum(&format!("sha256::{}sha256::{}", &hex[..28], &hex[..28])),
+        None
+    );
+    assert_eq!(parse_checksum(&format!("sha256::{hex}sha256::{hex}")), None);
+}
+
+#[test]
+fn test_format_sri() {
+    let hex = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    assert_eq!(
+        format_sri(&format!("SHA256::{hex}")).as_deref(),
+        Some("sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=")
+    );
+    assert_eq!(format_sri(&format!("blake2b::{hex}{hex}")), None);
+    assert_eq!(format_sri("SKIP"), None);
+}
+
+#[test]
+fn test_hasher() {
+    let hash = |algo: ChecksumAlgo| {
+        let mut hasher: Box<dyn ChecksumHasher> = Box::new(algo.hasher());
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        finalize(hasher)
+    };
+
+    assert_eq!(hash(ChecksumAlgo::Sha256), crate::HELLO_SHA256);
+    assert_eq!(
+        hash(ChecksumAlgo::Sha512),
+        "sha512::309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f"
+    );
+    assert_eq!(
+        hash(ChecksumAlgo::Blake2b),
+        "blake2b::021ced8799296ceca557832ab941a50b4a11f83478cf141f51f933f653ab9fbcc05a037cddbed06e309bf334942c4e58cdf1a46e237911ccd7fcf9787cbc7fd0"
+    );
+}
@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use reqwest::StatusCode;
+
+use crate::GroupError;
+use crate::ParseErrors;
+
+/// Class of a failure, for callers to react to each differently.
+///
+/// Returned by the entry points of this crate, e.g.
+/// [`update_from_str`](crate::update_from_str), see [`Self::class`] for the
+/// class of a failure wrapped in a [`Self::Group`] or [`Self::Other`]. Other
+/// functions return an [`eyre::Report`] whose chain contains this error when the
+/// failure falls in one of these classes, see [`Self::find`].
+#[derive(Debug)]
+pub enum UpdateChecksumError {
+    /// The spec does not parse.
+    Parse(ParseErrors),
+    /// A source could not be fetched, e.g. the connection failed, dropped or
+    /// timed out.
+    Network { url: String, source: reqwest::Error },
+    /// A `pypi` source lacks its package name or its `version=`.
+    PypiResolution { pkg: String, ver: Option<String> },
+    /// A `pypi` source asks for a `type=` other than `sdist` or `wheel`, or for a
+    /// `tag=` that is not `<python>-<abi>-<platform>` or of an sdist.
+    PypiDistribution { pkg: String },
+    /// A `crates` source lacks its crate name or its `version=`.
+    CratesResolution { name: String, ver: Option<String> },
+    /// A `github` source is not `owner/repo`, or lacks its `tag=` or `ref=`.
+    GithubResolution { repo: String },
+    /// A source was answered with a 4xx or 5xx status.
+    HttpStatus { url: String, status: StatusCode },
+    /// A source ended short of, or beyond, its `Content-Length`.
+    Truncated {
+        url: String,
+        expected: u64,
+        received: u64,
+    },
+    /// A source named like an archive was answered with an HTML page, see
+    /// [`UpdateChecksumOptions::check_html`](crate::UpdateChecksumOptions::check_html).
+    HtmlPage { url: String },
+    /// A source is not in the [`SourceStore`](crate::SourceStore) of an
+    /// [`offline`](crate::UpdateChecksumOptions::offline) run, or the releases of
+    /// an unversioned `pypi` source, which are never stored, are needed.
+    NotStored { url: String },
+    /// A `CHKSUMS` group failed to update.
+    Group(GroupError),
+    /// Any other failure, e.g. of a command substitution.
+    Other(eyre::Report),
+}
+
+impl UpdateChecksumError {
+    /// Returns the most specific class of this failure, i.e. of the first source
+    /// of a [`Self::Group`] that failed, or found in the chain of a
+    /// [`Self::Other`], or itself.
+    pub fn class(&self) -> &Self {
+        let inner = match self {
+            Self::Group(e) => e.failures.first().and_then(|(_, e)| Self::find(e)),
+            Self::Other(e) => Self::find(e),
+            _ => None,
+        };
+
+        inner.map_or(self, Self::class)
+    }
+
+    /// Returns the first error of this type in the chain of `e`, e.g. the cause of
+    /// a [`GroupError`](crate::GroupError).
+    pub fn find(e: &eyre::Report) -> Option<&Self> {
+        e.chain().find_map(|x| x.downcast_ref())
+    }
+}
+
+impl Display for UpdateChecksumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => e.fmt(f),
+            Self::Network { url, source } if source.is_timeout() => {
+                write!(f, "Timed out downloading {url}")
+            }
+            Self::Network { url, .. } => write!(f, "Failed to download {url}"),
+            Self::PypiResolution { pkg, ver: None } => {
+                write!(f, "pypi source {pkg} has no version=")
+            }
+            Self::PypiResolution { ver: Some(ver), .. } => {
+                write!(f, "pypi source of version {ver} has no package name")
+            }
+            Self::PypiDistribution { pkg } => write!(
+                f,
+                "pypi source {pkg} needs type=sdist, or type=wheel, optionally with a \
+                 tag= like py3-none-any"
+            ),
+            Self::CratesResolution { name, ver: None } => {
+                write!(f, "crates source {name} has no version=")
+            }
+            Self::CratesResolution { ver: Some(ver), .. } => {
+                write!(f, "crates source of version {ver} has no crate name")
+            }
+            Self::GithubResolution { repo } => write!(
+                f,
+                "github source {repo} needs an owner/repo, and either tag=, optionally \
+                 with asset=, or ref="
+            ),
+            Self::HttpStatus { url, status } => write!(f, "{url} returned {status}"),
+            Self::Truncated {
+                url,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Truncated download of {url}: expected {expected} bytes, got {received}"
+            ),
+            Self::HtmlPage { url } => write!(
+                f,
+                "{url} returned an HTML page instead of an archive, e.g. an error page"
+            ),
+            Self::NotStored { url } => write!(
+                f,
+                "{url} is not in the source store, which offline runs are limited to"
+            ),
+            Self::Group(e) => e.fmt(f),
+            // the rest of the chain is the source
+            Self::Other(e) => e.chain().next().expect("not empty").fmt(f),
+        }
+    }
+}
+
+impl Error for UpdateChecksumError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Network { source, .. } => Some(source),
+            Self::Group(e) => e.source(),
+            Self::Other(e) => e.chain().nth(1),
+            _ => None,
+        }
+    }
+}
+
+impl From<eyre::Report> for UpdateChecksumError {
+    fn from(e: eyre::Report) -> Self {
+        // only unwrapped when not under a context, which would be lost
+        let top = e.chain().next().expect("not empty");
+        if top.is::<Self>() {
+            e.downcast().expect("checked above")
+        } else if top.is::<GroupError>() {
+            Self::Group(e.downcast().expect("checked above"))
+        } else {
+            Self::Other(e)
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use reqwest::Url;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+/// Limit of the downloads in progress from each host, on top of the overall
+/// [`UpdateChecksumOptions::threads`](crate::UpdateChecksumOptions::threads).
+///
+/// Clones share the same limits.
+#[derive(Debug, Clone)]
+pub struct HostLimiter {
+    per_host: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimiter {
+    pub fn new(per_host: usize) -> Self {
+        Self {
+            per_host: per_host.max(1),
+            hosts: Arc::default(),
+        }
+    }
+
+    /// Waits until a download from the host of `url` is allowed, for as long as
+    /// the returned permit is held.
+    pub(crate) async fn acquire(&self, url: &str) -> OwnedSemaphorePermit {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|x| x.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone();
+
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+}
+
+#[tokio::test]
+async fn test_host_limiter() {
+    let limiter = HostLimiter::new(2);
+    let _a = limiter.acquire("https://example.org/a.tar.gz").await;
+    let b = limiter.acquire("https://EXAMPLE.org/b.tar.gz").await;
+    // another host has its own limit
+    let _c = limiter.acquire("https://example.com/c.tar.gz").await;
+
+    let third = limiter.acquire("https://example.org/d.tar.gz");
+    tokio::pin!(third);
+    assert!(futures::poll!(&mut third).is_pending());
+    drop(b);
+    assert!(futures::poll!(&mut third).is_ready());
+}
@@ -1,24 +1,699 @@
+mod cache;
+mod checksum;
+mod error;
+mod hosts;
+mod resume;
+#[cfg(feature = "openpgp")]
+mod signature;
+mod store;
+mod throttle;
+mod validators;
+
+pub use cache::ResponseCache;
+pub use checksum::format_checksum;
+pub use checksum::format_sri;
+pub use checksum::parse_checksum;
+pub use checksum::ChecksumAlgo;
+pub use checksum::ChecksumHasher;
+pub use checksum::CustomHasher;
+pub use error::UpdateChecksumError;
+pub use hosts::HostLimiter;
+pub use store::SourceStore;
+pub use throttle::RateLimiter;
+pub use validators::SourceValidators;
+
 use abbs_meta_apml::ParseError;
+use eyre::bail;
+use eyre::eyre;
 use eyre::ContextCompat;
 use eyre::Result;
-use faster_hex::hex_string;
+use eyre::WrapErr;
 use futures::StreamExt;
+use glob::Pattern;
 use log::debug;
 use log::warn;
-use reqwest::header::HeaderValue;
+use reqwest::header::ACCEPT_RANGES;
 use reqwest::header::CONTENT_LENGTH;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::RANGE;
 use reqwest::Client;
 use reqwest::ClientBuilder;
-use sha2::Digest;
-use sha2::Sha256;
+use reqwest::Method;
+use reqwest::NoProxy;
+use reqwest::Proxy;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
 use tokio::task::spawn_blocking;
+use tokio_util::sync::CancellationToken;
 
-const VCS: &[&str] = &["git", "bzr", "svn", "hg", "bk"];
+const VCS: &[&str] = &["git", "bzr", "svn", "hg", "bk", "fossil", "pijul"];
 const UA: &str = "curl/8.10.0";
+const STAMP_PREFIX: &str = "# checksums updated by ";
+const MAX_EXPANSION_DEPTH: usize = 16;
+/// Default [`UpdateChecksumOptions::indent`], aligning continuation lines after
+/// `CHKSUMS="`.
+pub const DEFAULT_INDENT: usize = 9;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Options controlling how checksums are updated, taken by every entry point
+/// such as [`update_from_str_with_options`] and [`get_new_spec_with_options`].
+///
+/// Set the options needed over the defaults, so that adding an option changes no
+/// signature, either with [`UpdateChecksumOptions::builder`]:
+///
+/// ```
+/// # use abbs_update_checksum_core::UpdateChecksumOptions;
+/// # use std::time::Duration;
+/// let opts = UpdateChecksumOptions::builder()
+///     .threads(8)
+///     .retries(2)
+///     .timeout(Duration::from_secs(30))
+///     .keep_going(true)
+///     .build();
+/// ```
+///
+/// or with the fields:
+///
+/// ```
+/// # use abbs_update_checksum_core::UpdateChecksumOptions;
+/// let opts = UpdateChecksumOptions {
+///     threads: 8,
+///     retries: 2,
+///     keep_going: true,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct UpdateChecksumOptions {
+    /// Maximum number of concurrent downloads.
+    pub threads: usize,
+    /// How many times a download is retried after a connection error or a
+    /// 408, 429 or 5xx response.
+    pub retries: usize,
+    /// Keep the bytes of a `GET` download in a temporary file, so that a retry
+    /// resumes with a `Range` request when the server advertised
+    /// `Accept-Ranges: bytes`, instead of starting over.
+    pub resume: bool,
+    /// Fail when fewer or more bytes are received than announced with
+    /// `Content-Length`, disabled for servers misreporting it.
+    pub check_length: bool,
+    /// Fail when a source named like an archive, e.g. `.tar.gz`, is answered with
+    /// an HTML page, typically a mirror's "not found" or rate limiting page sent
+    /// with a 200 status, disabled for the rare legitimate one.
+    pub check_html: bool,
+    /// Limit on connecting and on each request as a whole, including reading the
+    /// body, `None` for no limit.
+    pub timeout: Option<Duration>,
+    /// Extra source types recorded as `SKIP`, merged with the built-in VCS types.
+    pub skip_types: Vec<String>,
+    /// Decompress `.tar.zst`/`.tar.xz` sources on the fly to record their uncompressed size.
+    pub record_uncompressed_size: bool,
+    /// Write the size in bytes of each source into `CHKSIZE`/`CHKSIZE__<arch>`,
+    /// aligned with its `CHKSUMS`, with `SKIP` for sources not downloaded.
+    pub write_sizes: bool,
+    /// Keep the old value of a `CHKSUMS` group that failed to update instead of
+    /// aborting, see [`UpdateChecksumResult::failed_groups`].
+    pub partial: bool,
+    /// Keep the old checksum of each source that failed to download, or `SKIP` if
+    /// there is none, and record the others, see
+    /// [`UpdateChecksumResult::failed_sources`]. Takes precedence over `partial`.
+    /// A group failing to resolve keeps its old value, as with `partial`.
+    pub keep_going: bool,
+    /// Provenance recorded in a `# checksums updated by <stamp>` comment above `CHKSUMS`.
+    pub stamp: Option<String>,
+    /// Aborts in-flight downloads when cancelled.
+    pub cancel: Option<CancellationToken>,
+    /// Sources always recorded as `SKIP`.
+    pub ignore: IgnoreList,
+    /// Variables overriding their assignment in the spec, e.g. `VER`, see [`apply_defines`].
+    pub defines: Vec<(String, String)>,
+    /// Spec whose variables, except `SRCS` and `CHKSUMS`, are visible to the
+    /// updated file, e.g. the package `spec` when updating `autobuild/defines`.
+    pub inherit: Option<String>,
+    /// Make [`get_new_spec`] fail instead of returning a rewritten spec that no
+    /// longer parses.
+    pub validate_output: bool,
+    /// Write algorithm names in uppercase, e.g. `SHA256::<hex>`.
+    pub uppercase_algo: bool,
+    /// Strip the query of URLs, e.g. the signature of pre-signed URLs, in logs and
+    /// results. The URLs are still fetched as is and `SRCS` is never rewritten.
+    pub strip_query: bool,
+    /// Make [`get_new_spec`] re-render the existing checksums instead of
+    /// downloading anything, see [`normalize_from_str`].
+    pub normalize_only: bool,
+    /// Only update the `CHKSUMS` variables found in the spec, leaving out those of
+    /// `SRCS` variables without one, e.g. of an architecture only built from VCS.
+    pub no_create: bool,
+    /// Only update the checksums used by these architectures, e.g. `amd64`: their
+    /// `CHKSUMS__<arch>`, and `CHKSUMS` for those without a `SRCS__<arch>`. Every
+    /// `CHKSUMS` is updated if empty.
+    pub archs: Vec<String>,
+    /// Base URL, e.g. of an independent mirror, every source is fetched again from
+    /// to check that both downloads produce the same checksum.
+    pub cross_check: Option<String>,
+    /// OpenPGP public keys the detached signatures of sources given with `sig=`
+    /// must be made by, armored or binary.
+    pub keyring: Option<PathBuf>,
+    /// Verify every downloaded source without `sig=` too, against the signature at
+    /// its URL with `.sig` appended, or `.asc` if there is none, failing when it is
+    /// missing or bad. Requires a [`Self::keyring`].
+    pub verify_signatures: bool,
+    /// Fail instead of only logging warnings, i.e. when a command substitution in
+    /// `SRCS` has to be recorded as `SKIP`, or the `SKIP` positions of a group moved.
+    pub strict: bool,
+    /// Keep the existing checksums, only moving them to match the `SKIP` positions
+    /// of `SRCS` when they can be matched confidently, i.e. when a group has at
+    /// most one source that is not `SKIP`. Other groups are downloaded as usual.
+    pub repair: bool,
+    /// Algorithm of the checksums of sources without `algo=`, instead of the one
+    /// of their existing checksum, or SHA-256.
+    pub algorithm: Option<ChecksumAlgo>,
+    /// Keep a single-line `CHKSUMS` on one line instead of writing one checksum per
+    /// line.
+    pub keep_single_line: bool,
+    /// Indentation of the continuation lines of a `CHKSUMS` without any yet, in
+    /// spaces. Those of a `CHKSUMS` with continuation lines are kept.
+    pub indent: usize,
+    /// Resolve `pypi` sources without `version=` to the latest release of their
+    /// package, queried from the PyPI JSON API before anything is downloaded.
+    pub pypi_latest: bool,
+    /// With [`Self::pypi_latest`], also consider pre-releases and development
+    /// releases, e.g. `2.0rc1`.
+    pub pypi_prerelease: bool,
+    /// Hash every source with this instead, replacing existing checksums of any
+    /// algorithm and ignoring `algo=`.
+    pub hasher: Option<CustomHasher>,
+    /// How `CHKSUMS` groups made only of `SKIP` are written.
+    pub skip_style: SkipStyle,
+    /// Proxy every request is sent through. A `unix://` URL names a Unix domain
+    /// socket, e.g. of a caching sidecar, requests are then sent over as they would
+    /// be to the origin server.
+    pub proxy: Option<String>,
+    /// Directory the relative paths of local sources, given as `file://` URLs or
+    /// as paths without a scheme, are resolved against, e.g. the package directory.
+    pub base_dir: Option<PathBuf>,
+    /// Checksums of the URLs hashed so far, reused for later occurrences of the
+    /// same URL in the run, shared by clones.
+    pub cache: ResponseCache,
+    /// Validators of the sources hashed by previous runs. A source whose existing
+    /// checksum is the one recorded for its URL is not downloaded again if a
+    /// `HEAD` request answers with the same `ETag`, `Last-Modified` and
+    /// `Content-Length`. Every downloaded source with an `ETag` or a
+    /// `Last-Modified` is recorded.
+    pub validators: Option<SourceValidators>,
+    /// Limit of the bytes per second received by all downloads together, shared
+    /// by clones, e.g. by the packages updated at once.
+    pub max_rate: Option<RateLimiter>,
+    /// Limit of the downloads in progress from each host, so that sources sharing
+    /// a mirror do not all hit it at once, shared by clones.
+    pub per_host: Option<HostLimiter>,
+    /// Directory the bodies of `GET` downloads are kept in, keyed by URL, and read
+    /// from instead of downloading them again, e.g. in later runs.
+    pub store: Option<SourceStore>,
+    /// Fail for sources not in [`Self::store`] instead of downloading them, for
+    /// runs without network access.
+    pub offline: bool,
+}
+
+impl Default for UpdateChecksumOptions {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            retries: 0,
+            resume: false,
+            check_length: true,
+            check_html: true,
+            timeout: None,
+            skip_types: vec![],
+            record_uncompressed_size: false,
+            write_sizes: false,
+            partial: false,
+            keep_going: false,
+            stamp: None,
+            cancel: None,
+            ignore: IgnoreList::default(),
+            defines: vec![],
+            inherit: None,
+            validate_output: false,
+            uppercase_algo: false,
+            strip_query: false,
+            normalize_only: false,
+            no_create: false,
+            archs: vec![],
+            cross_check: None,
+            keyring: None,
+            verify_signatures: false,
+            strict: false,
+            repair: false,
+            algorithm: None,
+            keep_single_line: false,
+            indent: DEFAULT_INDENT,
+            pypi_latest: false,
+            pypi_prerelease: false,
+            hasher: None,
+            skip_style: SkipStyle::default(),
+            proxy: None,
+            base_dir: None,
+            cache: ResponseCache::default(),
+            validators: None,
+            max_rate: None,
+            per_host: None,
+            store: None,
+            offline: false,
+        }
+    }
+}
+
+/// Builder of [`UpdateChecksumOptions`], starting from the defaults, see
+/// [`UpdateChecksumOptions::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct UpdateChecksumOptionsBuilder(UpdateChecksumOptions);
+
+impl UpdateChecksumOptionsBuilder {
+    pub fn build(self) -> UpdateChecksumOptions {
+        self.0
+    }
+}
+
+/// Generates a setter for each option, those of `Option` fields taking the
+/// value itself.
+macro_rules! setters {
+    ($($name:ident: $ty:ty,)* ; $($opt:ident: $opt_ty:ty,)*) => {
+        impl UpdateChecksumOptionsBuilder {
+            $(
+                #[doc = concat!("Sets [`UpdateChecksumOptions::", stringify!($name), "`].")]
+                pub fn $name(mut self, $name: $ty) -> Self {
+                    self.0.$name = $name;
+                    self
+                }
+            )*
+            $(
+                #[doc = concat!("Sets [`UpdateChecksumOptions::", stringify!($opt), "`].")]
+                pub fn $opt(mut self, $opt: $opt_ty) -> Self {
+                    self.0.$opt = Some($opt);
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters! {
+    threads: usize,
+    retries: usize,
+    resume: bool,
+    check_length: bool,
+    check_html: bool,
+    skip_types: Vec<String>,
+    record_uncompressed_size: bool,
+    write_sizes: bool,
+    partial: bool,
+    keep_going: bool,
+    ignore: IgnoreList,
+    defines: Vec<(String, String)>,
+    validate_output: bool,
+    uppercase_algo: bool,
+    strip_query: bool,
+    normalize_only: bool,
+    no_create: bool,
+    archs: Vec<String>,
+    verify_signatures: bool,
+    strict: bool,
+    repair: bool,
+    keep_single_line: bool,
+    indent: usize,
+    pypi_latest: bool,
+    pypi_prerelease: bool,
+    skip_style: SkipStyle,
+    cache: ResponseCache,
+    offline: bool,
+    ;
+    timeout: Duration,
+    stamp: String,
+    cancel: CancellationToken,
+    inherit: String,
+    cross_check: String,
+    keyring: PathBuf,
+    algorithm: ChecksumAlgo,
+    hasher: CustomHasher,
+    proxy: String,
+    base_dir: PathBuf,
+    validators: SourceValidators,
+    max_rate: RateLimiter,
+    per_host: HostLimiter,
+    store: SourceStore,
+}
+
+impl UpdateChecksumOptions {
+    /// Returns a builder of options, starting from the defaults.
+    pub fn builder() -> UpdateChecksumOptionsBuilder {
+        UpdateChecksumOptionsBuilder::default()
+    }
+
+    /// Returns the path of a local source, read from disk instead of downloaded.
+    fn local_path(&self, url: &str) -> Option<PathBuf> {
+        let path = match url.strip_prefix("file://") {
+            Some(path) => path,
+            None if !url.contains("://") => url,
+            None => return None,
+        };
+
+        Some(match &self.base_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        })
+    }
+
+    /// Returns whether the `SRCS` variable `k` is used by one of [`Self::archs`].
+    fn selects(&self, k: &str, context: &HashMap<String, String>) -> bool {
+        if self.archs.is_empty() {
+            return true;
+        }
+
+        let keys = srcs_keys(context);
+        let arch_of = |k: &str| k.split_once("__").map(|(_, arch)| arch.to_lowercase());
+        match arch_of(k) {
+            Some(arch) => self.archs.iter().any(|x| x.to_lowercase() == arch),
+            // the default of the architectures without their own
+            None => self
+                .archs
+                .iter()
+                .any(|x| !keys.iter().any(|k| arch_of(k) == Some(x.to_lowercase()))),
+        }
+    }
+
+    fn is_skip_type(&self, typ: &str) -> bool {
+        let typ = typ.trim().to_lowercase();
+
+        VCS.contains(&typ.as_str()) || self.skip_types.iter().any(|x| x.to_lowercase() == typ)
+    }
+}
+
+/// How a `CHKSUMS` group is written when all of its sources are `SKIP`, e.g.
+/// when every source is a VCS checkout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SkipStyle {
+    /// One `SKIP` per source.
+    #[default]
+    Full,
+    /// A single `SKIP`.
+    Single,
+    /// Remove the `CHKSUMS` variable.
+    Omit,
+}
+
+impl SkipStyle {
+    fn apply(&self, checksums: Vec<String>) -> Vec<String> {
+        if checksums.is_empty() || checksums.iter().any(|x| x != "SKIP") {
+            return checksums;
+        }
+
+        match self {
+            Self::Full => checksums,
+            Self::Single => vec!["SKIP".to_string()],
+            Self::Omit => vec![],
+        }
+    }
+}
+
+impl FromStr for SkipStyle {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(Self::Full),
+            "single" => Ok(Self::Single),
+            "omit" => Ok(Self::Omit),
+            _ => bail!("Unknown SKIP style: {s}, expected full, single or omit"),
+        }
+    }
+}
+
+/// Sources always recorded as `SKIP`, usually loaded from the
+/// `.abbs-checksum-ignore` file at the root of an ABBS tree.
+///
+/// Each non-empty line not starting with `#` is either a URL glob, or a package
+/// name glob followed by a URL glob:
+///
+/// ```text
+/// # skipped in every package
+/// https://example.org/nightly/*
+/// # skipped in packages matching `foo*` only
+/// foo* https://example.org/foo-*.tar.gz
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreList {
+    rules: Vec<(Option<Pattern>, Pattern)>,
+}
+
+impl IgnoreList {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut rules = vec![];
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let rule = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [url] => (None, Pattern::new(url)?),
+                [pkg, url] => (Some(Pattern::new(pkg)?), Pattern::new(url)?),
+                _ => bail!("Illegal ignore rule at line {}: {line}", i + 1),
+            };
+
+            rules.push(rule);
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Returns the rules that apply to `pkg`, as package-independent rules.
+    pub fn for_package(&self, pkg: &str) -> Self {
+        let rules = self
+            .rules
+            .iter()
+            .filter(|(p, _)| p.as_ref().map(|p| p.matches(pkg)).unwrap_or(true))
+            .map(|(_, url)| (None, url.clone()))
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Returns whether `url` matches a package-independent rule.
+    pub fn matches(&self, url: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|(pkg, pattern)| pkg.is_none() && pattern.matches(url))
+    }
+}
+
+/// Result of a checksum update.
+#[derive(Debug, Default)]
+pub struct UpdateChecksumResult {
+    /// New checksums, keyed by `CHKSUMS` variable name.
+    pub checksums: HashMap<String, Vec<String>>,
+    /// Checksums found in the spec before updating, keyed by `CHKSUMS` variable name.
+    pub old_checksums: HashMap<String, Vec<String>>,
+    /// Checksums of every downloaded source, in no particular order.
+    pub sources: Vec<SourceChecksum>,
+    /// Uncompressed size of compressed tarball sources, keyed by URL.
+    ///
+    /// Only filled when [`UpdateChecksumOptions::record_uncompressed_size`] is set.
+    pub uncompressed_sizes: HashMap<String, u64>,
+    /// Sizes of the sources of the downloaded groups, keyed by `CHKSIZE` variable
+    /// name.
+    ///
+    /// Only filled when [`UpdateChecksumOptions::write_sizes`] is set.
+    pub sizes: HashMap<String, Vec<String>>,
+    /// `CHKSUMS` groups left untouched because they failed to update.
+    ///
+    /// Only filled when [`UpdateChecksumOptions::partial`] is set.
+    pub failed_groups: Vec<(String, eyre::Report)>,
+    /// URL and error of the sources that failed to download, whose old checksum
+    /// was kept.
+    ///
+    /// Only filled when [`UpdateChecksumOptions::keep_going`] is set.
+    pub failed_sources: Vec<(String, eyre::Report)>,
+    /// Warnings logged during the update, see [`UpdateChecksumOptions::strict`].
+    pub warnings: Vec<String>,
+    /// `CHKSUMS` variables reordered to match their `SRCS` without downloading.
+    ///
+    /// Only filled when [`UpdateChecksumOptions::repair`] is set.
+    pub repaired: Vec<String>,
+    /// `CHKSUMS` variables whose number of entries differed from the number of
+    /// sources in their `SRCS`, i.e. that were probably edited by hand.
+    pub mismatched: Vec<String>,
+}
+
+impl UpdateChecksumResult {
+    /// Logs `msg` as a warning and records it.
+    fn warn(&mut self, msg: String) {
+        warn!("{msg}");
+        self.warnings.push(msg);
+    }
+
+    /// Fails if warnings were recorded and `opts` treats them as errors.
+    fn check_strict(&self, opts: &UpdateChecksumOptions) -> Result<()> {
+        if opts.strict && !self.warnings.is_empty() {
+            bail!(
+                "Warnings are treated as errors:\n  {}",
+                self.warnings.join("\n  ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether any checksum differs from the one found in the spec.
+    pub fn changed(&self) -> bool {
+        self.checksums.iter().any(|(k, v)| {
+            self.old_checksums
+                .get(k)
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+                != v.as_slice()
+        })
+    }
+
+    /// Returns the `CHKSUMS` variables whose `SKIP` entries moved, which usually
+    /// means `SRCS` was reordered without reordering `CHKSUMS`.
+    pub fn skip_positions_changed(&self) -> Vec<&str> {
+        let skip_positions = |v: &[String]| {
+            v.iter()
+                .enumerate()
+                .filter(|(_, x)| *x == "SKIP")
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+
+        let mut keys = self
+            .checksums
+            .iter()
+            // all-SKIP groups may have been shortened by `SkipStyle`
+            .filter(|(_, v)| !v.iter().all(|x| x == "SKIP"))
+            .filter(|(k, v)| {
+                self.old_checksums
+                    .get(*k)
+                    .map(|old| skip_positions(old) != skip_positions(v))
+                    .unwrap_or(false)
+            })
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        keys
+    }
+}
+
+/// A source given directly instead of through an ABBS spec.
+///
+/// Deserialized from objects of the form
+/// `{"type": "tbl", "options": ["rename=foo.tar.gz"], "url": "https://...", "arch": "amd64"}`,
+/// where `type` defaults to `tbl`, and `options` and `arch` are optional.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Source {
+    #[serde(rename = "type", default = "default_source_type")]
+    pub typ: String,
+    /// Extra `key=value` attributes, as in the `type::key=value::url` spec form.
+    #[serde(default)]
+    pub options: Vec<String>,
+    pub url: String,
+    #[serde(default)]
+    pub arch: Option<String>,
+}
+
+fn default_source_type() -> String {
+    "tbl".to_string()
+}
+
+impl Source {
+    /// Formats the source as a `SRCS` token.
+    fn to_token(&self) -> String {
+        let mut token = vec![self.typ.as_str()];
+        token.extend(self.options.iter().map(|x| x.as_str()));
+        token.push(&self.url);
+
+        token.join("::")
+    }
+}
+
+/// Checksum of a [`Source`], serialized as `{"type", "url", "arch", "checksum", "size"}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceChecksum {
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub url: String,
+    pub arch: Option<String>,
+    pub checksum: String,
+    /// Bytes downloaded, `None` for `SKIP` sources.
+    pub size: Option<u64>,
+}
+
+/// Error of a `CHKSUMS` group some sources of which failed to download,
+/// carrying the checksums of the others.
+#[derive(Debug)]
+pub struct GroupError {
+    /// `CHKSUMS` variable of the group.
+    pub key: String,
+    /// Checksums of the group, `None` for the sources that failed.
+    pub checksums: Vec<Option<String>>,
+    /// URL and error of every source that failed, in no particular order.
+    pub failures: Vec<(String, eyre::Report)>,
+}
+
+impl Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed = self.checksums.iter().filter(|x| x.is_none()).count();
+
+        write!(
+            f,
+            "{failed} of {} source(s) of {} failed",
+            self.checksums.len(),
+            self.key
+        )
+    }
+}
+
+impl Error for GroupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.failures.first().map(|(_, e)| e.as_ref())
+    }
+}
+
+/// Progress events reported to the callback of [`get_new_spec`].
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// Resolution of `total` sources has started.
+    ResolveStart { total: usize },
+    /// A source has been resolved to a URL or classified as `SKIP`.
+    Resolved,
+    /// A download has been queued.
+    DownloadQueued,
+    /// `inc` bytes of download task `index` (`total` bytes in size, 0 if unknown,
+    /// e.g. sent without `Content-Length`) have been hashed, from `url`, without its
+    /// query with [`UpdateChecksumOptions::strip_query`].
+    Downloading {
+        index: usize,
+        url: &'a str,
+        inc: usize,
+        total: u64,
+    },
+    /// Download task `index` has finished.
+    Downloaded { index: usize, total: u64 },
+}
 
 #[derive(Debug)]
 pub struct ParseErrors(Vec<ParseError>);
@@ -35,289 +710,4396 @@ impl Display for ParseErrors {
 
 impl Error for ParseErrors {}
 
-fn parse_from_str(
+/// Parses the variables of a spec, e.g. `VER` or `CHKUPDATE`, the way the
+/// checksums are updated.
+///
+/// With `allow_fallback_method`, a spec the strict APML parser rejects is read
+/// line by line as `NAME=value` assignments, with the quotes removed, instead of
+/// failing with [`UpdateChecksumError::Parse`]. Variables are not expanded.
+pub fn parse_context(
     s: &str,
     allow_fallback_method: bool,
-) -> Result<HashMap<String, String>, ParseErrors> {
+) -> Result<HashMap<String, String>, UpdateChecksumError> {
     let mut context = HashMap::new();
+    parse_from_str(s, &mut context, allow_fallback_method).map_err(UpdateChecksumError::Parse)?;
+
+    Ok(context)
+}
 
-    if let Err(e) = abbs_meta_apml::parse(s, &mut context) {
+fn parse_from_str(
+    s: &str,
+    context: &mut HashMap<String, String>,
+    allow_fallback_method: bool,
+) -> Result<(), ParseErrors> {
+    if let Err(e) = abbs_meta_apml::parse(s, context) {
         if !allow_fallback_method {
             return Err(ParseErrors(e));
         } else {
             warn!("{e:?}, buildit will use fallback method to parse file");
+            let mut names = vec![];
             for line in s.split('\n') {
                 let stmt = line.split_once('=');
                 if let Some((name, value)) = stmt {
                     context.insert(name.to_string(), value.replace('"', ""));
+                    names.push(name);
                 }
             }
+
+            // unlike APML, which already expanded them and unescaped `\$`
+            let raw = context.clone();
+            for name in names {
+                context.insert(name.to_string(), expand_vars(&raw[name], &raw));
+            }
         }
     }
 
-    Ok(context)
+    Ok(())
 }
 
 async fn update_all_checksum<C>(
     client: &Client,
     context: &mut HashMap<String, String>,
     cb: C,
-    threads: usize,
-) -> Result<()>
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult>
 where
-    C: Fn(bool, usize, usize, u64) + Copy,
+    C: Fn(Event) + Copy,
 {
     let mut src_chksum_map = HashMap::new();
+    let mut res = UpdateChecksumResult::default();
+
+    let keys = srcs_keys(context)
+        .into_iter()
+        .filter(|k| !opts.no_create || context.contains_key(&chksums_key(k)))
+        .filter(|k| opts.selects(k, context))
+        .collect::<Vec<_>>();
+
+    let total = keys.iter().map(|k| split_srcs(&context[k]).len()).sum();
+    cb(Event::ResolveStart { total });
+
+    // resolve everything first, so that resolution errors abort before any download,
+    // unless the group is to be kept
+    let mut groups = vec![];
+    for k in keys {
+        let v = &context[&k];
+        let old = context
+            .get(&chksums_key(&k))
+            .map(|x| x.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let count = split_srcs(v).len();
+        if !old.is_empty() && old.len() != count {
+            let key = chksums_key(&k);
+            res.warn(format!(
+                "{key} has {} entries but {k} has {count} sources, regenerating it",
+                old.len()
+            ));
+            res.mismatched.push(key);
+        }
+
+        // a failed lookup of the latest releases fails the group as well
+        let resolved = match pin_pypi_versions(client, v, opts).await {
+            Ok(v) => resolve_group(&v, &old, cb, opts),
+            Err(e) => Err(e),
+        };
+        match resolved {
+            Ok(resolved) => {
+                warn_resolved(&resolved, &old, opts, &mut res);
+
+                match opts.repair.then(|| repair_group(&resolved, &old)).flatten() {
+                    Some(repaired) => {
+                        if repaired != old {
+                            res.repaired.push(chksums_key(&k));
+                        }
+                        src_chksum_map.insert(k, opts.skip_style.apply(repaired));
+                    }
+                    None => groups.push((k, resolved, old)),
+                }
+            }
+            Err(e) if opts.partial || opts.keep_going => {
+                let key = chksums_key(&k);
+                warn!("Failed to resolve {k}, keeping the old value of {key}: {e}");
+                res.failed_groups.push((key, e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    res.check_strict(opts)?;
 
     let mut task_index = 0;
-    for (k, v) in context.clone() {
-        if k != "SRCS" && !k.starts_with("SRCS__") {
-            continue;
+    for (k, resolved, old) in groups {
+        let arch = k.split_once("__").map(|(_, arch)| arch);
+        let checksums =
+            download_group(client, &resolved, &mut task_index, cb, opts, arch, &mut res).await;
+
+        match checksums {
+            Ok(checksums) => {
+                src_chksum_map.insert(k, opts.skip_style.apply(checksums));
+            }
+            Err(e) if opts.keep_going && e.is::<GroupError>() => {
+                let e = e.downcast::<GroupError>().expect("checked above");
+                let checksums = keep_old_checksums(e, &old, &mut res);
+                src_chksum_map.insert(k, opts.skip_style.apply(checksums));
+            }
+            Err(e) if opts.partial => {
+                let key = chksums_key(&k);
+                warn!("Failed to update {key}, keeping its old value: {e}");
+                res.failed_groups.push((key, e));
+            }
+            Err(e) => return Err(e),
         }
+    }
 
-        let mut res = vec![];
+    for (k, v) in src_chksum_map {
+        context.insert(chksums_key(&k), v.join(" "));
+    }
 
-        let split = v.split_whitespace().collect::<Vec<_>>();
+    Ok(res)
+}
 
-        let mut tasks = vec![];
+/// Returns the checksums of a group, with the old checksum of each source that
+/// failed, or `SKIP` if there is none, recording their errors in `result`.
+fn keep_old_checksums(
+    e: GroupError,
+    old: &[&str],
+    result: &mut UpdateChecksumResult,
+) -> Vec<String> {
+    // old checksums only match their sources by position if no source was added
+    // or removed
+    let aligned = old.len() == e.checksums.len();
 
-        for (i, c) in split.iter().enumerate() {
-            let split = c.trim().split("::").collect::<Vec<_>>();
+    for (url, e) in &e.failures {
+        warn!("Failed to download {url}, keeping its old checksum: {e:#}");
+    }
+    result.failed_sources.extend(e.failures);
 
-            let typ = split.first().unwrap_or(&"tbl");
-            let mut src: Cow<str> = Cow::Borrowed(*split.last().unwrap_or(&""));
+    e.checksums
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| match (x, old.get(i).filter(|_| aligned)) {
+            (Some(x), _) => x,
+            (None, Some(old)) => old.to_string(),
+            (None, None) => {
+                warn!(
+                    "No old checksum for source {} of {}, recording SKIP",
+                    i + 1,
+                    e.key
+                );
+                "SKIP".to_string()
+            }
+        })
+        .collect()
+}
 
-            if typ.trim().to_lowercase() == "pypi" {
-                let ver = split
-                    .iter()
-                    .find_map(|x| x.strip_prefix("version="))
-                    .context("pypi stmt is illegal")?;
+/// Expands `$NAME` and `${NAME}` references to variables of `context`,
+/// including references inside the values of those variables, and the trimming
+/// forms `${NAME%pattern}`, `${NAME%%pattern}`, `${NAME#pattern}` and
+/// `${NAME##pattern}`, as APML does, for the values of the fallback parser.
+///
+/// Unknown variables are kept as is.
+fn expand_vars(s: &str, context: &HashMap<String, String>) -> String {
+    expand_vars_inner(s, context, 0)
+}
 
-                let url = get_pypi_download_url(&src, ver).context("pkg name is empty")?;
-                src = Cow::Owned(url);
+fn expand_vars_inner(s: &str, context: &HashMap<String, String>, depth: usize) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
             }
+        };
 
-            if VCS.contains(&typ.trim().to_lowercase().as_str()) {
-                res.push(Cow::Borrowed("SKIP"));
-            } else {
-                res.push(Cow::Borrowed(""));
-                let task = get_sha256(client, src, task_index, cb, i);
-                task_index += 1;
-                tasks.push(task);
+        let (name, trim) = split_trim(name);
+        match context.get(name) {
+            Some(v) if depth < MAX_EXPANSION_DEPTH => {
+                let v = expand_vars_inner(v, context, depth + 1);
+                match trim {
+                    Some((op, pattern)) => {
+                        let pattern = expand_vars_inner(pattern, context, depth + 1);
+                        out.push_str(trim_match(&v, op, &pattern));
+                    }
+                    None => out.push_str(&v),
+                }
+                rest = after;
             }
+            _ => out.push('$'),
         }
+    }
 
-        let tasks_res = futures::stream::iter(tasks)
-            .buffer_unordered(threads)
-            .collect::<Vec<_>>()
-            .await;
+    out.push_str(rest);
 
-        for c in tasks_res {
-            let (checksum, index) = c?;
-            res[index] = Cow::Owned(checksum);
-        }
+    out
+}
+
+/// Splits `NAME%pattern` and the other trimming forms into the name, and the
+/// operator and pattern if any.
+fn split_trim(expr: &str) -> (&str, Option<(&str, &str)>) {
+    let Some(end) = expr.find(['%', '#']) else {
+        return (expr, None);
+    };
+    let (name, rest) = expr.split_at(end);
+    let op_len = if rest[1..].starts_with(&rest[..1]) {
+        2
+    } else {
+        1
+    };
+
+    (name, Some(rest.split_at(op_len)))
+}
+
+/// Removes the shortest (`%`, `#`) or longest (`%%`, `##`) suffix (`%`) or
+/// prefix (`#`) of `value` matching the glob `pattern`, as the shell does.
+fn trim_match<'a>(value: &'a str, op: &str, pattern: &str) -> &'a str {
+    let Ok(pattern) = Pattern::new(pattern) else {
+        return value;
+    };
+    let mut bounds = value.char_indices().map(|(i, _)| i).chain([value.len()]);
+
+    let trimmed = match op {
+        "%" => bounds
+            .rev()
+            .find(|i| pattern.matches(&value[*i..]))
+            .map(|i| &value[..i]),
+        "%%" => bounds
+            .find(|i| pattern.matches(&value[*i..]))
+            .map(|i| &value[..i]),
+        "#" => bounds
+            .find(|i| pattern.matches(&value[..*i]))
+            .map(|i| &value[i..]),
+        _ => bounds
+            .rev()
+            .find(|i| pattern.matches(&value[..*i]))
+            .map(|i| &value[i..]),
+    };
+
+    trimmed.unwrap_or(value)
+}
+
+fn is_srcs_key(k: &str) -> bool {
+    k == "SRCS" || k.starts_with("SRCS__")
+}
 
-        src_chksum_map.insert(k, res);
+/// Returns the `SRCS` variables of `context`, sorted.
+fn srcs_keys(context: &HashMap<String, String>) -> Vec<String> {
+    let mut keys = context
+        .keys()
+        .filter(|k| is_srcs_key(k))
+        .cloned()
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    keys
+}
+
+fn is_chksums_key(k: &str) -> bool {
+    k == "CHKSUMS" || k.starts_with("CHKSUMS__")
+}
+
+/// Maps `SRCS`/`SRCS__<arch>` to its `CHKSUMS`/`CHKSUMS__<arch>` counterpart.
+fn chksums_key(srcs_key: &str) -> String {
+    match srcs_key.split_once("__") {
+        Some((_, arch)) => format!("CHKSUMS__{}", arch),
+        None => "CHKSUMS".to_string(),
     }
+}
 
-    for (k, v) in src_chksum_map {
-        let type_arch = k.split_once("__");
+/// Splits `SRCS` on whitespace, keeping `$(...)` command substitutions whole.
+fn split_srcs(srcs: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut depth = 0;
+    let mut prev = ' ';
 
-        if let Some((_, arch)) = type_arch {
-            let key = format!("CHKSUMS__{}", arch);
-            context.insert(key.to_string(), v.join(" "));
-        } else {
-            context.insert("CHKSUMS".to_string(), v.join(" "));
+    for (i, c) in srcs.char_indices() {
+        match c {
+            '(' if prev == '$' || depth > 0 => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                if let Some(start) = start.take() {
+                    tokens.push(&srcs[start..i]);
+                }
+                prev = c;
+                continue;
+            }
+            _ => {}
         }
+
+        start.get_or_insert(i);
+        prev = c;
     }
 
-    Ok(())
+    if let Some(start) = start {
+        tokens.push(&srcs[start..]);
+    }
+
+    tokens
 }
 
-fn get_pypi_download_url(pkg: &str, ver: &str) -> Option<String> {
-    let first_char = pkg.chars().next()?;
+/// A `SRCS` token of the form `type::key=value::...::url`.
+struct SrcToken<'a> {
+    typ: &'a str,
+    attrs: Vec<&'a str>,
+    url: &'a str,
+}
 
-    let url = format!(
-        "https://pypi.io/packages/source/{}/{}/{}-{}.tar.gz",
-        first_char, pkg, pkg, ver
-    );
+impl<'a> SrcToken<'a> {
+    fn parse(token: &'a str) -> Self {
+        let split = token.trim().split("::").collect::<Vec<_>>();
 
-    Some(url)
-}
+        match split.as_slice() {
+            [url] => Self {
+                typ: "tbl",
+                attrs: vec![],
+                url,
+            },
+            [typ, attrs @ .., url] => Self {
+                typ: typ.trim(),
+                attrs: attrs.to_vec(),
+                url,
+            },
+            [] => Self {
+                typ: "tbl",
+                attrs: vec![],
+                url: "",
+            },
+        }
+    }
 
-async fn get_sha256(
-    client: &Client,
-    src: Cow<'_, str>,
-    task_index: usize,
-    cb: impl (Fn(bool, usize, usize, u64)),
-    index: usize,
-) -> Result<(String, usize)> {
-    let mut sha256 = Sha256::new();
-    let resp = client.get(&*src).send().await?;
-    let mut resp = resp.error_for_status()?;
+    /// Returns the value of the `key=value` attribute.
+    fn attr(&self, key: &str) -> Option<&'a str> {
+        self.attrs
+            .iter()
+            .find_map(|x| x.split_once('=').and_then(|(k, v)| (k == key).then_some(v)))
+    }
+
+    /// Returns the values of every `key=value` attribute, in order.
+    fn attrs(&self, key: &'a str) -> impl Iterator<Item = &'a str> + '_ {
+        self.attrs
+            .iter()
+            .filter_map(move |x| x.split_once('=').and_then(|(k, v)| (k == key).then_some(v)))
+    }
+
+    /// Returns the algorithm requested with `algo=`, or `default`.
+    fn algo(&self, default: ChecksumAlgo) -> Result<ChecksumAlgo> {
+        self.attr("algo").map(|x| x.parse()).unwrap_or(Ok(default))
+    }
+
+    /// Returns the checksum declared inline, e.g. with `sha256=<hex>`.
+    fn expected(&self) -> Result<Option<(ChecksumAlgo, String)>> {
+        for algo in ChecksumAlgo::ALL {
+            let Some(hex) = self.attr(algo.name()) else {
+                continue;
+            };
 
-    let total_size = resp
-        .headers()
-        .get(CONTENT_LENGTH)
-        .map(|x| x.to_owned())
-        .unwrap_or(HeaderValue::from(0));
+            let checksum = format!("{algo}::{}", hex.to_lowercase());
+            if parse_checksum(&checksum).is_none() {
+                bail!("Malformed inline checksum: {checksum}");
+            }
 
-    let total_size = total_size
-        .to_str()
-        .ok()
-        .and_then(|x| x.parse::<u64>().ok())
-        .unwrap_or_default();
+            return Ok(Some((algo, checksum)));
+        }
 
-    while let Some(chunk) = resp.chunk().await? {
-        sha256.update(&chunk);
-        cb(false, task_index, chunk.len(), total_size);
+        Ok(None)
     }
+}
 
-    let s = spawn_blocking(move || format!("sha256::{}", hex_string(&sha256.finalize()))).await?;
+/// How a source is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceClass {
+    /// Downloaded and hashed.
+    Hash,
+    /// `SKIP` because of its type, e.g. a VCS.
+    Skip,
+    /// `SKIP` because of the [`IgnoreList`].
+    Ignored,
+    /// `SKIP` because its URL uses command substitution.
+    Unexpandable,
+}
 
-    cb(true, task_index, total_size as usize, total_size);
+impl Display for SourceClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Hash => "hash",
+            Self::Skip => "skip",
+            Self::Ignored => "ignored",
+            Self::Unexpandable => "unexpandable",
+        })
+    }
+}
 
-    Ok((s, index))
+/// A source of a spec, see [`list_sources`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedSource {
+    /// `SRCS` variable of the source.
+    pub key: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// URL of the source, resolved for PyPI, crates.io and GitHub sources, or the
+    /// package of a `pypi` source left to resolve to its latest release, see
+    /// [`UpdateChecksumOptions::pypi_latest`].
+    pub url: String,
+    pub class: SourceClass,
 }
 
-pub async fn update_from_str<C>(
+/// Lists the sources of a spec and how they are handled, without downloading
+/// anything.
+pub fn list_sources(
     s: &str,
-    cb: C,
-    threads: usize,
-) -> Result<HashMap<String, Vec<String>>>
-where
-    C: Fn(bool, usize, usize, u64) + Copy,
-{
-    let mut context = parse_from_str(s, false)?;
-    let client = ClientBuilder::new().user_agent(UA).referer(false).build()?;
+    opts: &UpdateChecksumOptions,
+) -> Result<Vec<ListedSource>, UpdateChecksumError> {
+    let context = parse_spec(s, opts)?;
+    let mut sources = vec![];
 
-    update_all_checksum(&client, &mut context, cb, threads).await?;
-    let mut new = HashMap::new();
+    for key in srcs_keys(&context) {
+        let tokens = split_srcs(&context[&key]);
+        // the latest releases are only looked up when updating
+        let unpinned = |c: &str| is_unpinned_pypi(&SrcToken::parse(c), opts);
+        let pinned = tokens
+            .iter()
+            .copied()
+            .filter(|&c| !unpinned(c))
+            .collect::<Vec<_>>();
+        let mut resolved = resolve_group(&pinned.join(" "), &[], |_| {}, opts)?.into_iter();
 
-    for (k, v) in context {
-        if k == "CHKSUMS" || k.starts_with("CHKSUMS__") {
-            let v = v
-                .split_whitespace()
-                .map(|x| x.to_string())
-                .collect::<Vec<_>>();
+        for c in tokens {
+            let (typ, url, class) = if unpinned(c) {
+                let token = SrcToken::parse(c);
+                (
+                    token.typ.to_string(),
+                    token.url.to_string(),
+                    SourceClass::Hash,
+                )
+            } else {
+                match resolved.next().expect("one per source") {
+                    Resolved::Skip { typ, url, class } => (typ, url, class),
+                    Resolved::Download { typ, url, .. } => (typ, url, SourceClass::Hash),
+                }
+            };
 
-            new.insert(k, v);
+            sources.push(ListedSource {
+                key: key.clone(),
+                typ,
+                url,
+                class,
+            });
         }
     }
 
-    Ok(new)
+    Ok(sources)
+}
+
+/// A source of a `SRCS` group, resolved before anything is downloaded.
+enum Resolved {
+    Skip {
+        typ: String,
+        url: String,
+        class: SourceClass,
+    },
+    Download {
+        typ: String,
+        url: String,
+        algo: ChecksumAlgo,
+        /// Checksum declared inline, verified instead of trusted blindly.
+        expected: Option<String>,
+        /// Set with `method=`, `GET` by default.
+        method: Method,
+        /// Request body set with `body=`.
+        body: Option<String>,
+        /// URL of the detached signature set with `sig=`.
+        sig: Option<String>,
+        /// Fallback URLs set with `mirror=`, tried in order when `url` fails.
+        mirrors: Vec<String>,
+        /// Existing checksum at the same position of `CHKSUMS`.
+        old: Option<String>,
+    },
 }
 
-pub async fn get_new_spec<C>(spec_inner: &mut String, cb: C, threads: usize) -> Result<()>
+/// Computes the checksums of a single `SRCS` group.
+async fn update_group<C>(
+    client: &Client,
+    srcs: &str,
+    task_index: &mut usize,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+    arch: Option<&str>,
+    result: &mut UpdateChecksumResult,
+) -> Result<Vec<String>>
 where
-    C: Fn(bool, usize, usize, u64) + Copy,
+    C: Fn(Event) + Copy,
 {
-    let new_checksum_map = update_from_str(&*spec_inner, cb, threads).await?;
+    let srcs = pin_pypi_versions(client, srcs, opts).await?;
+    let resolved = resolve_group(&srcs, &[], cb, opts)?;
+    warn_resolved(&resolved, &[], opts, result);
+    result.check_strict(opts)?;
 
-    debug!("{new_checksum_map:?}");
+    download_group(client, &resolved, task_index, cb, opts, arch, result).await
+}
 
-    update_spec_inner(new_checksum_map, spec_inner);
+/// Matches the existing checksums `old` of a group to its `resolved` sources.
+///
+/// `SKIP` entries go to the sources recorded as `SKIP`, which is only enough to
+/// place the others when there is at most one of them. Returns `None` unless the
+/// group has as many checksums of each kind as sources.
+fn repair_group(resolved: &[Resolved], old: &[&str]) -> Option<Vec<String>> {
+    let skip = |x: &&str| *x == "SKIP";
+    let downloads = resolved
+        .iter()
+        .filter(|x| matches!(x, Resolved::Download { .. }))
+        .count();
+    if old.len() != resolved.len() || old.iter().filter(|x| !skip(x)).count() != downloads {
+        return None;
+    }
 
-    Ok(())
-}
+    let aligned = resolved
+        .iter()
+        .zip(old)
+        .all(|(r, x)| matches!(r, Resolved::Skip { .. }) == skip(x));
+    if aligned {
+        return Some(old.iter().map(|x| x.to_string()).collect());
+    }
 
-fn update_spec_inner(new: HashMap<String, Vec<String>>, spec_inner: &mut String) {
-    for (k, v) in new {
-        let start = spec_inner.find(&k).unwrap();
-        let mut tmp_ref = spec_inner.as_str();
-        tmp_ref = &tmp_ref[start..];
-        let start_delimit = tmp_ref.find("\"").unwrap();
-        tmp_ref = &tmp_ref[start_delimit + 1..];
-        let end_delimit = tmp_ref.find("\"").unwrap();
-
-        debug!(
-            "replace range: {}",
-            &spec_inner[start..start + start_delimit + end_delimit + 2]
-        );
+    if downloads > 1 {
+        return None;
+    }
 
-        spec_inner.replace_range(
-            start..start + start_delimit + end_delimit + 2,
-            &format!("{k}=\"{}\"", &v.join(" \\\n         ")),
-        );
+    let mut hashes = old.iter().filter(|x| !skip(x));
+
+    Some(
+        resolved
+            .iter()
+            .map(|r| match r {
+                Resolved::Skip { .. } => "SKIP".to_string(),
+                Resolved::Download { .. } => hashes.next().unwrap().to_string(),
+            })
+            .collect(),
+    )
+}
+
+/// Warns about sources recorded as `SKIP` for lack of a URL, and about those
+/// whose existing checksum in `old` uses an algorithm that cannot be kept.
+fn warn_resolved(
+    resolved: &[Resolved],
+    old: &[&str],
+    opts: &UpdateChecksumOptions,
+    result: &mut UpdateChecksumResult,
+) {
+    for (i, c) in resolved.iter().enumerate() {
+        match c {
+            Resolved::Skip {
+                url,
+                class: SourceClass::Unexpandable,
+                ..
+            } => result.warn(format!(
+                "{url}: cannot expand command substitution, recording SKIP"
+            )),
+            // replaced in any case
+            Resolved::Download { .. } if opts.hasher.is_some() => {}
+            Resolved::Download { url, algo, .. } => {
+                let Some((old_algo, _)) = old.get(i).and_then(|x| x.split_once("::")) else {
+                    continue;
+                };
+                if old_algo.parse::<ChecksumAlgo>().is_err() {
+                    result.warn(format!(
+                        "{url}: unsupported algorithm {old_algo} of the existing checksum, \
+                         replacing it with {algo}"
+                    ));
+                }
+            }
+            Resolved::Skip { .. } => {}
+        }
     }
 }
 
-#[test]
-fn test_update_spec() {
-    let map1 = [("CHKSUMS".to_string(), vec!["sha256::xyz".to_string()])]
-        .into_iter()
-        .collect::<HashMap<_, _>>();
+/// Resolves the URLs of a `SRCS` group and classifies its `SKIP` sources,
+/// without downloading anything.
+///
+/// Sources without `algo=` keep the algorithm of the checksum at the same
+/// position of `old`, the existing `CHKSUMS` of the group, if any.
+fn resolve_group<C>(
+    srcs: &str,
+    old: &[&str],
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<Vec<Resolved>>
+where
+    C: Fn(Event) + Copy,
+{
+    let mut resolved = vec![];
 
-    let mut spec = r#"VER=5.115.0
-SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
-CHKSUMS="sha256::abc \
-         SKIP"
-CHKUPDATE="anitya::id=8762""#
-        .to_string();
+    for (i, c) in split_srcs(srcs).into_iter().enumerate() {
+        let token = SrcToken::parse(c);
 
-    update_spec_inner(map1, &mut spec);
+        let typ = token.typ.to_string();
+        let mut src: Cow<str> = Cow::Borrowed(token.url);
 
-    assert_eq!(
-        spec,
-        r#"VER=5.115.0
-SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
-CHKSUMS="sha256::xyz"
-CHKUPDATE="anitya::id=8762""#
-            .to_string()
-    );
+        if typ.to_lowercase() == "pypi" {
+            let ver = token
+                .attr("version")
+                .ok_or_else(|| UpdateChecksumError::PypiResolution {
+                    pkg: src.to_string(),
+                    ver: None,
+                })?;
 
-    let map1 = [("CHKSUMS".to_string(), vec!["sha256::xyz".to_string()])]
-        .into_iter()
-        .collect::<HashMap<_, _>>();
+            let dist = token.attr("type");
+            let url =
+                get_pypi_download_url(&src, ver, dist, token.attr("tag")).ok_or_else(|| {
+                    if src.is_empty() {
+                        UpdateChecksumError::PypiResolution {
+                            pkg: src.to_string(),
+                            ver: Some(ver.to_string()),
+                        }
+                    } else {
+                        UpdateChecksumError::PypiDistribution {
+                            pkg: src.to_string(),
+                        }
+                    }
+                })?;
+            src = Cow::Owned(url);
+        } else if typ.to_lowercase() == "crates" {
+            let ver =
+                token
+                    .attr("version")
+                    .ok_or_else(|| UpdateChecksumError::CratesResolution {
+                        name: src.to_string(),
+                        ver: None,
+                    })?;
 
-    let mut spec = r#"VER=5.115.0
+            let url = get_crates_download_url(&src, ver).ok_or_else(|| {
+                UpdateChecksumError::CratesResolution {
+                    name: src.to_string(),
+                    ver: Some(ver.to_string()),
+                }
+            })?;
+            src = Cow::Owned(url);
+        } else if typ.to_lowercase() == "github" {
+            let url = get_github_download_url(
+                &src,
+                token.attr("tag"),
+                token.attr("ref"),
+                token.attr("asset"),
+            )
+            .ok_or_else(|| UpdateChecksumError::GithubResolution {
+                repo: src.to_string(),
+            })?;
+            src = Cow::Owned(url);
+        }
+
+        let class = if src.contains("$(") || src.contains('`') {
+            SourceClass::Unexpandable
+        } else if opts.is_skip_type(&typ) {
+            SourceClass::Skip
+        } else if opts.ignore.matches(&src) {
+            SourceClass::Ignored
+        } else {
+            SourceClass::Hash
+        };
+
+        if class != SourceClass::Hash {
+            resolved.push(Resolved::Skip {
+                typ,
+                url: src.into_owned(),
+                class,
+            });
+            cb(Event::Resolved);
+        } else {
+            // a source added without a `CHKSUMS` slot yet gets the default
+            let default = opts
+                .algorithm
+                .or_else(|| {
+                    old.get(i)
+                        .and_then(|x| x.split_once("::"))
+                        .and_then(|(algo, _)| algo.parse().ok())
+                })
+                .unwrap_or_default();
+            let (algo, expected) = match token.expected()? {
+                Some((algo, expected)) => (algo, Some(expected)),
+                None => (token.algo(default)?, None),
+            };
+            let method = match token.attr("method") {
+                Some(x) => Method::from_bytes(x.to_uppercase().as_bytes())
+                    .map_err(|_| eyre!("Illegal HTTP method: {x}"))?,
+                None => Method::GET,
+            };
+            let sig = token.attr("sig").map(|x| x.to_string()).or_else(|| {
+                // only a single remote file can be signed at a URL derived from its own
+                let derive = opts.verify_signatures
+                    && method == Method::GET
+                    && !src.contains('|')
+                    && opts.local_path(&src).is_none();
+                derive.then(|| format!("{src}.sig"))
+            });
+            if sig.is_some() && opts.keyring.is_none() {
+                bail!("{src}: sig= requires a keyring to verify the signature with");
+            }
+            resolved.push(Resolved::Download {
+                typ,
+                url: src.into_owned(),
+                algo,
+                expected,
+                method,
+                body: token.attr("body").map(|x| x.to_string()),
+                sig,
+                mirrors: token.attrs("mirror").map(|x| x.to_string()).collect(),
+                old: old.get(i).map(|x| x.to_string()),
+            });
+            cb(Event::Resolved);
+            cb(Event::DownloadQueued);
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Downloads the sources of a resolved `SRCS` group and returns its checksums.
+async fn download_group<C>(
+    client: &Client,
+    resolved: &[Resolved],
+    task_index: &mut usize,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+    arch: Option<&str>,
+    result: &mut UpdateChecksumResult,
+) -> Result<Vec<String>>
+where
+    C: Fn(Event) + Copy,
+{
+    let mut res = vec![];
+    let mut tasks = vec![];
+
+    for (i, c) in resolved.iter().enumerate() {
+        match c {
+            Resolved::Skip { .. } => res.push("SKIP".to_string()),
+            Resolved::Download {
+                url,
+                algo,
+                method,
+                body,
+                sig,
+                mirrors,
+                old,
+                ..
+            } => {
+                res.push(String::new());
+                let task_index = *task_index;
+                let task = async move {
+                    let primary = Fetch {
+                        url,
+                        method: method.clone(),
+                        body: body.as_deref(),
+                        sig: sig.as_deref(),
+                        algo: *algo,
+                    };
+                    if let Some(hashed) =
+                        check_unchanged(client, &primary, old.as_deref(), opts).await
+                    {
+                        cb(Event::Downloaded {
+                            index: task_index,
+                            total: 0,
+                        });
+
+                        return Ok((hashed, i));
+                    }
+
+                    let mut errors = vec![];
+                    for candidate in std::iter::once(url).chain(mirrors) {
+                        let fetch = Fetch {
+                            url: candidate,
+                            method: method.clone(),
+                            body: body.as_deref(),
+                            sig: sig.as_deref(),
+                            algo: *algo,
+                        };
+                        match get_sha256(client, &fetch, task_index, cb, i, opts).await {
+                            Ok(res) => {
+                                // a local source has no mirror to compare with
+                                let cross_check = opts
+                                    .cross_check
+                                    .as_ref()
+                                    .filter(|_| opts.local_path(candidate).is_none());
+                                if let Some(base) = cross_check {
+                                    cross_check(client, &fetch, base, &res.0.checksum, opts)
+                                        .await?;
+                                }
+
+                                return Ok(res);
+                            }
+                            Err(e) if opts.cancel.as_ref().is_some_and(|x| x.is_cancelled()) => {
+                                return Err(e);
+                            }
+                            Err(e) => {
+                                if !mirrors.is_empty() {
+                                    warn!(
+                                        "Failed to download {}: {e}",
+                                        recorded_url(candidate, opts)
+                                    );
+                                }
+                                errors.push((candidate, e));
+                            }
+                        }
+                    }
+
+                    if errors.len() == 1 {
+                        return Err(errors.pop().unwrap().1);
+                    }
+
+                    let tried = errors
+                        .iter()
+                        .map(|(url, e)| format!("\n  {}: {e}", recorded_url(url, opts)))
+                        .collect::<String>();
+
+                    Err(eyre!(
+                        "All mirrors of {} failed:{tried}",
+                        recorded_url(url, opts)
+                    ))
+                };
+                *task_index += 1;
+                tasks.push(async move { task.await.map_err(|e| (i, e)) });
+            }
+        }
+    }
+
+    let tasks_res = futures::stream::iter(tasks)
+        .buffer_unordered(opts.threads)
+        .collect::<Vec<_>>()
+        .await;
+
+    let url_of = |index: usize| match &resolved[index] {
+        Resolved::Download { url, .. } => recorded_url(url, opts).to_string(),
+        Resolved::Skip { .. } => unreachable!(),
+    };
+
+    let mut sizes = vec!["SKIP".to_string(); resolved.len()];
+    let mut failures = vec![];
+    for c in tasks_res {
+        let (
+            Hashed {
+                checksum,
+                size,
+                uncompressed_size,
+            },
+            index,
+        ) = match c {
+            Ok(c) => c,
+            Err((index, e)) => {
+                let url = url_of(index);
+                let missing = matches!(
+                    UpdateChecksumError::find(&e),
+                    Some(UpdateChecksumError::HttpStatus { status, .. })
+                        if *status == StatusCode::NOT_FOUND
+                );
+                let e = match &resolved[index] {
+                    Resolved::Download { typ, .. }
+                        if missing && typ.eq_ignore_ascii_case("github") =>
+                    {
+                        e.wrap_err("No such tag, ref or release asset on GitHub")
+                    }
+                    _ => e,
+                };
+                // which of several sources of the same host failed is not obvious
+                // from the URL alone
+                let srcs = match arch {
+                    Some(arch) => format!("SRCS__{arch}"),
+                    None => "SRCS".to_string(),
+                };
+                failures.push((url, e.wrap_err(format!("Source {} of {srcs}", index + 1))));
+                continue;
+            }
+        };
+
+        let Resolved::Download { typ, expected, .. } = &resolved[index] else {
+            unreachable!()
+        };
+        let url = url_of(index);
+
+        if let Some(expected) = expected.as_ref().filter(|x| **x != checksum) {
+            let e = eyre!("Checksum mismatch for {url}: declared {expected}, got {checksum}");
+            failures.push((url, e));
+            continue;
+        }
+
+        if let Some(size) = uncompressed_size {
+            result.uncompressed_sizes.insert(url.clone(), size);
+        }
+
+        let checksum = checksum::with_algo_case(&checksum, opts.uppercase_algo);
+
+        result.sources.push(SourceChecksum {
+            typ: typ.clone(),
+            url,
+            arch: arch.map(|x| x.to_string()),
+            checksum: checksum.clone(),
+            size: Some(size),
+        });
+        res[index] = checksum;
+        sizes[index] = size.to_string();
+    }
+
+    if !failures.is_empty() {
+        return Err(GroupError {
+            key: match arch {
+                Some(arch) => format!("CHKSUMS__{arch}"),
+                None => "CHKSUMS".to_string(),
+            },
+            checksums: res
+                .into_iter()
+                .map(|x| (!x.is_empty()).then_some(x))
+                .collect(),
+            failures,
+        }
+        .into());
+    }
+
+    if opts.write_sizes {
+        let key = match arch {
+            Some(arch) => format!("CHKSIZE__{arch}"),
+            None => "CHKSIZE".to_string(),
+        };
+        result.sizes.insert(key, sizes);
+    }
+
+    Ok(res)
+}
+
+/// Returns the source archive of the `tag` or `git_ref` of the GitHub repository
+/// `owner/repo`, as served by codeload, or the release `asset` of `tag`.
+fn get_github_download_url(
+    repo: &str,
+    tag: Option<&str>,
+    git_ref: Option<&str>,
+    asset: Option<&str>,
+) -> Option<String> {
+    let (owner, name) = repo.split_once('/')?;
+    if owner.is_empty() || name.is_empty() || name.contains('/') {
+        return None;
+    }
+
+    let url = match (tag, git_ref, asset) {
+        (Some(tag), None, Some(asset)) => format!(
+            "https://github.com/{}/{}/releases/download/{}/{}",
+            owner, name, tag, asset
+        ),
+        (Some(tag), None, None) => format!(
+            "https://codeload.github.com/{}/{}/tar.gz/refs/tags/{}",
+            owner, name, tag
+        ),
+        (None, Some(git_ref), None) => format!(
+            "https://codeload.github.com/{}/{}/tar.gz/{}",
+            owner, name, git_ref
+        ),
+        _ => return None,
+    };
+
+    Some(url)
+}
+
+fn get_crates_download_url(name: &str, ver: &str) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let url = format!(
+        "https://static.crates.io/crates/{}/{}-{}.crate",
+        name, name, ver
+    );
+
+    Some(url)
+}
+
+/// Returns the sdist of version `ver` of the PyPI package `pkg`, or with
+/// `dist` set to `wheel` the wheel of its compatibility `tag`, `py3-none-any` by
+/// default.
+fn get_pypi_download_url(
+    pkg: &str,
+    ver: &str,
+    dist: Option<&str>,
+    tag: Option<&str>,
+) -> Option<String> {
+    let first_char = pkg.chars().next()?;
+
+    let url = match dist.map(|x| x.to_lowercase()).as_deref() {
+        None | Some("sdist") if tag.is_none() => format!(
+            "https://pypi.io/packages/source/{}/{}/{}-{}.tar.gz",
+            first_char, pkg, pkg, ver
+        ),
+        Some("wheel") => {
+            let tag = tag.unwrap_or("py3-none-any");
+            // `<python>-<abi>-<platform>`, the first of which names the directory
+            let python = match tag.split('-').collect::<Vec<_>>()[..] {
+                [python, abi, platform]
+                    if !python.is_empty() && !abi.is_empty() && !platform.is_empty() =>
+                {
+                    python
+                }
+                _ => return None,
+            };
+            format!(
+                "https://pypi.io/packages/{}/{}/{}/{}-{}-{}.whl",
+                python,
+                first_char,
+                pkg,
+                pkg.replace('-', "_"),
+                ver,
+                tag
+            )
+        }
+        _ => return None,
+    };
+
+    Some(url)
+}
+
+/// Releases of a package as listed by `https://pypi.org/pypi/<pkg>/json`.
+#[derive(Debug, Deserialize)]
+struct PypiProject {
+    releases: HashMap<String, Vec<PypiFile>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PypiFile {
+    /// `sdist` or `bdist_wheel`.
+    packagetype: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Returns whether `token` is a `pypi` source to resolve to the latest release of
+/// its package, see [`UpdateChecksumOptions::pypi_latest`].
+fn is_unpinned_pypi(token: &SrcToken, opts: &UpdateChecksumOptions) -> bool {
+    opts.pypi_latest
+        && token.typ.eq_ignore_ascii_case("pypi")
+        && token.attr("version").is_none()
+        && !token.url.is_empty()
+}
+
+/// With [`UpdateChecksumOptions::pypi_latest`], adds the `version=` of the
+/// latest release to the `pypi` sources of `srcs` lacking one.
+async fn pin_pypi_versions(
+    client: &Client,
+    srcs: &str,
+    opts: &UpdateChecksumOptions,
+) -> Result<String> {
+    let mut tokens = vec![];
+
+    for c in split_srcs(srcs) {
+        let token = SrcToken::parse(c);
+        if !is_unpinned_pypi(&token, opts) {
+            tokens.push(c.to_string());
+            continue;
+        }
+
+        let pkg = token.url;
+        let api = format!("https://pypi.org/pypi/{pkg}/json");
+        // releases are not stored, they change over time
+        if opts.offline {
+            return Err(UpdateChecksumError::NotStored { url: api }.into());
+        }
+        let project = async {
+            let body = cancellable(opts.cancel.as_ref(), pkg, client.get(&api).send())
+                .await??
+                .error_for_status()?
+                .bytes()
+                .await?;
+
+            Ok::<PypiProject, eyre::Report>(serde_json::from_slice(&body)?)
+        }
+        .await
+        .wrap_err_with(|| format!("Failed to get the releases of pypi source {pkg}"))?;
+
+        let wheel = token
+            .attr("type")
+            .is_some_and(|x| x.eq_ignore_ascii_case("wheel"));
+        let ver = latest_pypi_version(&project, wheel, opts.pypi_prerelease)
+            .with_context(|| format!("pypi source {pkg} has no release to resolve to"))?;
+        debug!("Resolved pypi source {pkg} to version {ver}");
+
+        let rest = token
+            .attrs
+            .iter()
+            .chain(std::iter::once(&token.url))
+            .copied()
+            .collect::<Vec<_>>();
+        tokens.push(format!("{}::version={ver}::{}", token.typ, rest.join("::")));
+    }
+
+    Ok(tokens.join(" "))
+}
+
+/// Returns the highest version of `project` publishing a file that is not yanked,
+/// an sdist or a wheel.
+fn latest_pypi_version(project: &PypiProject, wheel: bool, prerelease: bool) -> Option<&str> {
+    let packagetype = if wheel { "bdist_wheel" } else { "sdist" };
+
+    project
+        .releases
+        .iter()
+        .filter(|(_, files)| {
+            files
+                .iter()
+                .any(|x| x.packagetype == packagetype && !x.yanked)
+        })
+        .filter_map(|(ver, _)| Some((ver.as_str(), pypi_version_key(ver)?)))
+        .filter(|(_, key)| prerelease || key.1 >= FINAL_RELEASE)
+        .max_by(|a, b| a.1.cmp(&b.1))
+        .map(|(ver, _)| ver)
+}
+
+/// Rank of final releases in [`pypi_version_key`], below which are pre-releases.
+const FINAL_RELEASE: u8 = 4;
+
+/// Suffixes of PEP 440 versions with their rank, in the order they are tried.
+const PYPI_SUFFIXES: &[(&str, u8)] = &[
+    ("dev", 0),
+    ("alpha", 1),
+    ("a", 1),
+    ("beta", 2),
+    ("b", 2),
+    ("rc", 3),
+    ("c", 3),
+    ("post", 5),
+];
+
+/// Returns an ordering key of a PEP 440 version: its release numbers, the rank of
+/// its suffix among `.dev`, `a`, `b`, `rc`, none and `.post`, and the number of
+/// that suffix. `None` for versions that do not parse, e.g. with a local part.
+fn pypi_version_key(ver: &str) -> Option<(Vec<u64>, u8, u64)> {
+    let ver = ver.to_lowercase();
+    let end = ver
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(ver.len());
+    let (release, suffix) = ver.split_at(end);
+    let release = release
+        .trim_end_matches('.')
+        .split('.')
+        .map(|x| x.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    let suffix = suffix.trim_start_matches(['.', '-', '_']);
+    if suffix.is_empty() {
+        return Some((release, FINAL_RELEASE, 0));
+    }
+
+    let (rank, number) = PYPI_SUFFIXES
+        .iter()
+        .find_map(|(prefix, rank)| Some((*rank, suffix.strip_prefix(prefix)?)))?;
+    let number = number.trim_start_matches(['.', '-', '_']);
+    let digits = number
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(number.len());
+
+    Some((release, rank, number[..digits].parse().unwrap_or(0)))
+}
+
+/// Checksum of a downloaded source.
+#[derive(Debug, Clone)]
+pub(crate) struct Hashed {
+    checksum: String,
+    /// Bytes downloaded.
+    size: u64,
+    uncompressed_size: Option<u64>,
+}
+
+/// What to download for a source.
+struct Fetch<'a> {
+    url: &'a str,
+    method: Method,
+    body: Option<&'a str>,
+    sig: Option<&'a str>,
+    algo: ChecksumAlgo,
+}
+
+/// Downloads and hashes a source, retrying transient failures up to
+/// [`UpdateChecksumOptions::retries`] times with exponential backoff.
+async fn get_sha256(
+    client: &Client,
+    fetch: &Fetch<'_>,
+    task_index: usize,
+    cb: impl Fn(Event),
+    index: usize,
+    opts: &UpdateChecksumOptions,
+) -> Result<(Hashed, usize)> {
+    let mut attempt = 0;
+    let mut partial = (opts.resume && fetch.method == Method::GET)
+        .then(resume::Partial::new)
+        .transpose()?;
+
+    loop {
+        let once = get_sha256_once(
+            client,
+            fetch,
+            partial.as_mut(),
+            task_index,
+            &cb,
+            index,
+            opts,
+        );
+        match once.await {
+            Err(e) if attempt < opts.retries && is_retryable(&e) => {
+                attempt += 1;
+                let delay = RETRY_DELAY * 2u32.saturating_pow(attempt as u32 - 1);
+                warn!(
+                    "Retrying {} in {}s, attempt {attempt} of {}: {e}",
+                    recorded_url(fetch.url, opts),
+                    delay.as_secs(),
+                    opts.retries
+                );
+                cancellable(opts.cancel.as_ref(), fetch.url, tokio::time::sleep(delay)).await?;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns the existing checksum `old` of a source without downloading it if a
+/// `HEAD` request shows it unchanged, see [`UpdateChecksumOptions::validators`].
+async fn check_unchanged(
+    client: &Client,
+    fetch: &Fetch<'_>,
+    old: Option<&str>,
+    opts: &UpdateChecksumOptions,
+) -> Option<Hashed> {
+    let (validators, old) = (opts.validators.as_ref()?, old?);
+    // what the body is hashed for besides its checksum, or not fetched over HTTP
+    if fetch.method != Method::GET
+        || fetch.sig.is_some()
+        || opts.record_uncompressed_size
+        || fetch.url.contains('|')
+        || opts.local_path(fetch.url).is_some()
+        || opts.offline
+    {
+        return None;
+    }
+
+    let name = recorded_url(fetch.url, opts);
+    match cancellable(opts.cancel.as_ref(), name, client.head(fetch.url).send()).await {
+        Ok(Ok(resp)) if resp.status().is_success() => {
+            validators.unchanged(fetch.url, fetch.algo, old, resp.headers())
+        }
+        Ok(Ok(resp)) => {
+            debug!("HEAD {name} returned {}, downloading it", resp.status());
+            None
+        }
+        Ok(Err(e)) => {
+            debug!("HEAD {name} failed, downloading it: {}", e.without_url());
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Downloads the signature at `sig`, or with
+/// [`UpdateChecksumOptions::verify_signatures`] the armored one next to it if
+/// `sig` ends with `.sig` and is missing, and returns its URL.
+#[cfg(feature = "openpgp")]
+async fn get_signature(
+    client: &Client,
+    sig: &str,
+    opts: &UpdateChecksumOptions,
+) -> Result<(String, Vec<u8>)> {
+    let mut candidates = vec![sig.to_string()];
+    if let Some(base) = sig.strip_suffix(".sig").filter(|_| opts.verify_signatures) {
+        candidates.push(format!("{base}.asc"));
+    }
+
+    if let Some(store) = &opts.store {
+        for candidate in &candidates {
+            if let Some(path) = store.get(candidate) {
+                return Ok((candidate.clone(), tokio::fs::read(&path).await?));
+            }
+        }
+    }
+    if opts.offline {
+        return Err(UpdateChecksumError::NotStored {
+            url: sig.to_string(),
+        }
+        .into());
+    }
+
+    for candidate in &candidates {
+        let resp = cancellable(
+            opts.cancel.as_ref(),
+            candidate,
+            client.get(candidate).send(),
+        )
+        .await??;
+        if resp.status() == StatusCode::NOT_FOUND {
+            continue;
+        }
+
+        let expected = resp.content_length();
+        let signature = resp.error_for_status()?.bytes().await?.to_vec();
+        // only stored with the size it was announced with, see `SourceStore::get`
+        if let (Some(store), Some(expected)) = (&opts.store, expected) {
+            let mut entry = store.entry(candidate)?;
+            entry.save(&signature)?;
+            entry.persist(expected)?;
+        }
+
+        return Ok((candidate.clone(), signature));
+    }
+
+    bail!("No signature found at {}", candidates.join(" or "))
+}
+
+/// Returns whether `e` is a connection error or a status worth retrying, i.e.
+/// 408, 429 or 5xx.
+fn is_retryable(e: &eyre::Report) -> bool {
+    match e.downcast_ref::<UpdateChecksumError>() {
+        Some(UpdateChecksumError::HttpStatus { status, .. }) => {
+            status.is_server_error()
+                || *status == StatusCode::REQUEST_TIMEOUT
+                || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        Some(UpdateChecksumError::Network { source: e, .. }) => {
+            e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+        }
+        _ => false,
+    }
+}
+
+async fn get_sha256_once(
+    client: &Client,
+    fetch: &Fetch<'_>,
+    mut partial: Option<&mut resume::Partial>,
+    task_index: usize,
+    cb: impl Fn(Event),
+    index: usize,
+    opts: &UpdateChecksumOptions,
+) -> Result<(Hashed, usize)> {
+    let src = fetch.url;
+    let algo = fetch.algo;
+    // responses to other methods or with a body depend on the body, and
+    // signatures have to be verified every time
+    let cacheable = fetch.method == Method::GET && fetch.body.is_none() && fetch.sig.is_none();
+
+    #[cfg(not(feature = "openpgp"))]
+    if fetch.sig.is_some() {
+        bail!("{src}: verifying sig= requires building with the `openpgp` feature");
+    }
+
+    // held until hashed, so that other occurrences of the URL wait to reuse it
+    let mut slot = None;
+    if cacheable {
+        match opts.cache.get(src, algo).await {
+            Ok(hashed) => {
+                cb(Event::Downloaded {
+                    index: task_index,
+                    total: 0,
+                });
+
+                return Ok((hashed, index));
+            }
+            Err(x) => slot = Some(x),
+        }
+    }
+
+    // `a.part1|a.part2` is hashed as the concatenation of its parts, in order
+    let parts = src.split('|').collect::<Vec<_>>();
+    // the body of other requests depends on more than the URL
+    let store = opts
+        .store
+        .as_ref()
+        .filter(|_| fetch.method == Method::GET && fetch.body.is_none());
+
+    let mut hasher: Box<dyn ChecksumHasher> = match &opts.hasher {
+        Some(custom) => custom.build(),
+        None => Box::new(algo.hasher()),
+    };
+    let mut size_counter = if opts.record_uncompressed_size {
+        SizeCounter::for_url(parts[parts.len() - 1])?
+    } else {
+        None
+    };
+    let cancel = opts.cancel.as_ref();
+    let mut total_size = 0;
+    // unless every part has a `Content-Length`, the total is reported as unknown
+    let mut sized = true;
+    let mut size = 0;
+    let mut headers = vec![];
+    #[cfg(feature = "openpgp")]
+    let mut spool = fetch.sig.map(|_| signature::Spool::new()).transpose()?;
+    let mut feed = |chunk: &[u8]| -> Result<()> {
+        hasher.update(chunk);
+        size += chunk.len() as u64;
+        if let Some(counter) = &mut size_counter {
+            counter.update(chunk)?;
+        }
+        #[cfg(feature = "openpgp")]
+        if let Some(spool) = &mut spool {
+            spool.update(chunk)?;
+        }
+
+        Ok(())
+    };
+
+    // a resumed download continues with the part the previous attempt stopped in
+    let first_part = partial.as_ref().map(|x| x.part).unwrap_or_default();
+    if let Some(partial) = &partial {
+        total_size = partial.done_size;
+    }
+
+    for (i, part) in parts.iter().enumerate().skip(first_part) {
+        let name = recorded_url(part, opts);
+
+        let local = opts
+            .local_path(part)
+            .or_else(|| store.and_then(|x| x.get(part)));
+        if let Some(path) = local {
+            let mut file = tokio::fs::File::open(&path)
+                .await
+                .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+            total_size += file.metadata().await?.len();
+            // read again from the start, which is cheap
+            if let Some(partial) = &mut partial {
+                partial.restart_part()?;
+                if i == first_part {
+                    partial.replay(&mut feed)?;
+                }
+            }
+
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = cancellable(cancel, name, file.read(&mut buf)).await??;
+                if n == 0 {
+                    break;
+                }
+                feed(&buf[..n])?;
+                if let Some(partial) = &mut partial {
+                    partial.save(&buf[..n])?;
+                }
+                cb(Event::Downloading {
+                    index: task_index,
+                    url: name,
+                    inc: n,
+                    total: if sized { total_size } else { 0 },
+                });
+            }
+
+            if let Some(partial) = &mut partial {
+                partial.next_part(total_size);
+            }
+            continue;
+        }
+        if opts.offline {
+            return Err(UpdateChecksumError::NotStored {
+                url: name.to_string(),
+            }
+            .into());
+        }
+        // the URL is already part of the message, without the query with `strip_query`
+        let scrub = |e: reqwest::Error| UpdateChecksumError::Network {
+            url: name.to_string(),
+            source: e.without_url(),
+        };
+
+        // held until the body is read
+        let _permit = match &opts.per_host {
+            Some(limiter) => Some(cancellable(cancel, name, limiter.acquire(part)).await?),
+            None => None,
+        };
+        let mut req = client.request(fetch.method.clone(), *part);
+        if let Some(body) = fetch.body {
+            req = req.body(body.to_string());
+        }
+        let offset = partial.as_ref().map(|x| x.offset()).unwrap_or_default();
+        if offset > 0 {
+            req = req.header(RANGE, format!("bytes={offset}-"));
+        }
+
+        let mut resp = cancellable(cancel, name, req.send())
+            .await?
+            .map_err(scrub)?;
+        let status = resp.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(UpdateChecksumError::HttpStatus {
+                url: name.to_string(),
+                status,
+            }
+            .into());
+        }
+        let html = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(is_html_type);
+        let check_html = opts.check_html && offset == 0 && expects_archive(part);
+        if check_html && html {
+            return Err(UpdateChecksumError::HtmlPage {
+                url: name.to_string(),
+            }
+            .into());
+        }
+        headers.push(resp.headers().clone());
+
+        let resumed = offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+        if let Some(partial) = &mut partial {
+            if resumed {
+                debug!("Resuming {name} from byte {offset}");
+                total_size += offset;
+            } else {
+                // the server ignored the range, start the part over
+                partial.restart_part()?;
+            }
+            partial.ranges = resp
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_some_and(|x| x.as_bytes() == b"bytes");
+            if i == first_part {
+                partial.replay(&mut feed)?;
+            }
+        }
+
+        let part_size = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<u64>().ok());
+
+        // a resumed body lacks its start, and one without a size can not be told
+        // apart from a truncated one
+        let mut entry = store
+            .filter(|_| !resumed && part_size.is_some())
+            .map(|x| x.entry(part))
+            .transpose()?;
+
+        sized &= part_size.is_some();
+        total_size += part_size.unwrap_or_default();
+
+        let mut received = 0;
+        while let Some(chunk) = cancellable(cancel, name, resp.chunk())
+            .await?
+            .map_err(scrub)?
+        {
+            if let Some(limiter) = &opts.max_rate {
+                cancellable(cancel, name, limiter.acquire(chunk.len())).await?;
+            }
+            // e.g. served as application/octet-stream anyway
+            if check_html && received == 0 && looks_like_html(&chunk) {
+                return Err(UpdateChecksumError::HtmlPage {
+                    url: name.to_string(),
+                }
+                .into());
+            }
+            received += chunk.len() as u64;
+            feed(&chunk)?;
+            if let Some(partial) = &mut partial {
+                partial.save(&chunk)?;
+            }
+            if let Some(entry) = &mut entry {
+                entry.save(&chunk)?;
+            }
+            cb(Event::Downloading {
+                index: task_index,
+                url: name,
+                inc: chunk.len(),
+                total: if sized { total_size } else { 0 },
+            });
+        }
+
+        // a truncated body must not end up as a checksum
+        if let Some(expected) = part_size.filter(|x| *x != received && opts.check_length) {
+            return Err(UpdateChecksumError::Truncated {
+                url: name.to_string(),
+                expected,
+                received,
+            }
+            .into());
+        }
+
+        // only complete bodies are stored, even without `check_length`
+        if let Some(entry) = entry.filter(|_| part_size == Some(received)) {
+            entry.persist(received)?;
+        }
+        if let Some(partial) = &mut partial {
+            partial.next_part(total_size);
+        }
+    }
+
+    #[cfg(feature = "openpgp")]
+    if let (Some(spool), Some(sig)) = (spool, fetch.sig) {
+        let (sig, signature) = get_signature(client, sig, opts)
+            .await
+            .wrap_err_with(|| format!("Failed to verify {src}"))?;
+        // checked in `resolve_group`
+        let keyring = opts.keyring.clone().unwrap();
+
+        spawn_blocking(move || spool.verify(&signature, &keyring))
+            .await?
+            .wrap_err_with(|| format!("Failed to verify {src} against {sig}"))?;
+    }
+
+    let hashed = Hashed {
+        checksum: spawn_blocking(move || checksum::finalize(hasher)).await?,
+        size,
+        uncompressed_size: size_counter.map(|x| x.finish()).transpose()?,
+    };
+    if let Some(slot) = slot {
+        slot.insert(src, &headers, &hashed);
+    }
+    if let ([headers], true) = (headers.as_slice(), cacheable) {
+        if let Some(validators) = &opts.validators {
+            validators.insert(src, headers, &hashed);
+        }
+    }
+
+    cb(Event::Downloaded {
+        index: task_index,
+        total: total_size,
+    });
+
+    Ok((hashed, index))
+}
+
+/// Fetches the source again from `base` and fails unless both checksums agree.
+async fn cross_check(
+    client: &Client,
+    fetch: &Fetch<'_>,
+    base: &str,
+    checksum: &str,
+    opts: &UpdateChecksumOptions,
+) -> Result<()> {
+    let url = fetch.url;
+    let alt = url
+        .split('|')
+        .map(|x| cross_check_url(x, base))
+        .collect::<Option<Vec<_>>>()
+        .with_context(|| format!("Failed to map {url} onto {base}"))?
+        .join("|");
+
+    let alt_fetch = Fetch {
+        url: &alt,
+        method: fetch.method.clone(),
+        body: fetch.body,
+        // the signature was already verified over the primary download
+        sig: None,
+        algo: fetch.algo,
+    };
+    let (alt, _) = get_sha256(client, &alt_fetch, 0, |_| {}, 0, opts).await?;
+    let alt_checksum = alt.checksum;
+
+    if alt_checksum != checksum {
+        bail!(
+            "Checksum mismatch between mirrors, possible tampering:\n  \
+             {}: {checksum}\n  {}: {alt_checksum}",
+            recorded_url(url, opts),
+            recorded_url(&alt, opts)
+        );
+    }
+
+    Ok(())
+}
+
+/// Maps `url` onto `base`, keeping its path and query, e.g.
+/// `https://example.org/foo.tar.gz` onto `https://mirror.example.org/pub` gives
+/// `https://mirror.example.org/pub/foo.tar.gz`.
+fn cross_check_url(url: &str, base: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let path = rest.find('/').map(|i| &rest[i..]).unwrap_or("/");
+
+    Some(format!("{}{path}", base.trim_end_matches('/')))
+}
+
+/// Returns `url` as recorded in logs and results.
+fn recorded_url<'a>(url: &'a str, opts: &UpdateChecksumOptions) -> &'a str {
+    if opts.strip_query {
+        url.split_once('?').map(|(x, _)| x).unwrap_or(url)
+    } else {
+        url
+    }
+}
+
+/// Awaits `fut`, or bails out as soon as `cancel` is triggered.
+async fn cancellable<F: Future>(
+    cancel: Option<&CancellationToken>,
+    src: &str,
+    fut: F,
+) -> Result<F::Output> {
+    match cancel {
+        Some(token) => tokio::select! {
+            _ = token.cancelled() => bail!("Download of {src} was cancelled"),
+            res = fut => Ok(res),
+        },
+        None => Ok(fut.await),
+    }
+}
+
+/// Counts decompressed bytes of a compressed tarball fed chunk by chunk.
+enum SizeCounter {
+    Zstd(Box<zstd::stream::write::Decoder<'static, ByteCounter>>),
+    Xz(Box<xz2::write::XzDecoder<io::Sink>>),
+}
+
+/// File extensions of archives, which are never HTML pages.
+const ARCHIVE_EXTENSIONS: &[&str] = &[
+    ".tar", ".gz", ".tgz", ".xz", ".txz", ".bz2", ".tbz", ".tbz2", ".zst", ".tzst", ".lz", ".lzma",
+    ".zip", ".7z", ".whl", ".crate", ".gem", ".jar", ".deb", ".rpm",
+];
+
+/// Returns whether `url` names an archive, see [`ARCHIVE_EXTENSIONS`].
+fn expects_archive(url: &str) -> bool {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .to_ascii_lowercase();
+
+    ARCHIVE_EXTENSIONS.iter().any(|x| path.ends_with(x))
+}
+
+/// Returns whether the `Content-Type` `value` is HTML, whatever its parameters.
+fn is_html_type(value: &str) -> bool {
+    let mime = value.split(';').next().unwrap_or_default().trim();
+
+    mime.eq_ignore_ascii_case("text/html") || mime.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+/// Returns whether the first bytes of a body are the start of an HTML page.
+fn looks_like_html(start: &[u8]) -> bool {
+    let blank = start.iter().take_while(|x| x.is_ascii_whitespace()).count();
+    let start = &start[blank..];
+
+    [&b"<!doctype html"[..], b"<html"].iter().any(|x| {
+        start
+            .get(..x.len())
+            .is_some_and(|y| y.eq_ignore_ascii_case(x))
+    })
+}
+
+impl SizeCounter {
+    fn for_url(url: &str) -> io::Result<Option<Self>> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+
+        let counter = if path.ends_with(".tar.zst") || path.ends_with(".tzst") {
+            let decoder = zstd::stream::write::Decoder::new(ByteCounter(0))?;
+            Some(Self::Zstd(Box::new(decoder)))
+        } else if path.ends_with(".tar.xz") || path.ends_with(".txz") {
+            Some(Self::Xz(Box::new(xz2::write::XzDecoder::new(io::sink()))))
+        } else {
+            None
+        };
+
+        Ok(counter)
+    }
+
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Zstd(decoder) => decoder.write_all(data),
+            Self::Xz(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn finish(self) -> io::Result<u64> {
+        match self {
+            Self::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.get_ref().0)
+            }
+            Self::Xz(mut decoder) => {
+                decoder.try_finish()?;
+                Ok(decoder.total_out())
+            }
+        }
+    }
+}
+
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn build_client(opts: &UpdateChecksumOptions) -> Result<Client> {
+    let mut builder = ClientBuilder::new().user_agent(UA).referer(false);
+
+    if let Some(timeout) = opts.timeout {
+        builder = builder.connect_timeout(timeout).timeout(timeout);
+    }
+
+    match opts.proxy.as_deref() {
+        Some(proxy) if proxy.starts_with("unix://") => {
+            let path = &proxy["unix://".len()..];
+            #[cfg(unix)]
+            {
+                builder = builder.unix_socket(path);
+            }
+            #[cfg(not(unix))]
+            bail!("Unix domain socket proxy {path} is not supported on this platform");
+        }
+        Some(proxy) => {
+            let no_proxy = NoProxy::from_env();
+            builder = builder.proxy(
+                Proxy::all(proxy)
+                    .wrap_err("Illegal proxy URL")?
+                    .no_proxy(no_proxy),
+            );
+        }
+        None => {
+            for proxy in env_proxies(|name| std::env::var(name).ok())? {
+                builder = builder.proxy(proxy);
+            }
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Returns the proxies configured with `HTTP_PROXY` and `HTTPS_PROXY` for their
+/// scheme, then `ALL_PROXY` for any, skipping the hosts of `NO_PROXY`.
+///
+/// Lowercase names take precedence, as with curl.
+fn env_proxies(var: impl Fn(&str) -> Option<String>) -> Result<Vec<Proxy>> {
+    let var = |name: &str| {
+        var(&name.to_lowercase())
+            .or_else(|| var(name))
+            .filter(|x| !x.is_empty())
+    };
+    let no_proxy = var("NO_PROXY").and_then(|x| NoProxy::from_string(&x));
+
+    let mut proxies = vec![];
+    if let Some(url) = var("HTTP_PROXY") {
+        proxies.push(Proxy::http(url).wrap_err("Illegal HTTP_PROXY")?);
+    }
+    if let Some(url) = var("HTTPS_PROXY") {
+        proxies.push(Proxy::https(url).wrap_err("Illegal HTTPS_PROXY")?);
+    }
+    if let Some(url) = var("ALL_PROXY") {
+        proxies.push(Proxy::all(url).wrap_err("Illegal ALL_PROXY")?);
+    }
+
+    Ok(proxies
+        .into_iter()
+        .map(|x| x.no_proxy(no_proxy.clone()))
+        .collect())
+}
+
+/// Computes checksums of sources given without a spec, in the same order.
+pub async fn update_from_sources<C>(
+    sources: &[Source],
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<Vec<SourceChecksum>, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    let client = build_client(opts)?;
+    let srcs = sources
+        .iter()
+        .map(|x| x.to_token())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    cb(Event::ResolveStart {
+        total: sources.len(),
+    });
+
+    let mut task_index = 0;
+    let mut res = UpdateChecksumResult::default();
+    let checksums = update_group(&client, &srcs, &mut task_index, cb, opts, None, &mut res).await?;
+
+    Ok(sources
+        .iter()
+        .zip(checksums)
+        .map(|(src, checksum)| SourceChecksum {
+            typ: src.typ.clone(),
+            url: src.url.clone(),
+            arch: src.arch.clone(),
+            size: res
+                .sources
+                .iter()
+                .find(|x| x.url == recorded_url(&src.url, opts))
+                .and_then(|x| x.size),
+            checksum,
+        })
+        .collect())
+}
+
+fn parse_spec(s: &str, opts: &UpdateChecksumOptions) -> Result<HashMap<String, String>> {
+    let mut context = HashMap::new();
+    if let Some(base) = &opts.inherit {
+        parse_from_str(base, &mut context, false).map_err(UpdateChecksumError::Parse)?;
+        context.retain(|k, _| !is_srcs_key(k) && !is_chksums_key(k));
+    }
+    parse_from_str(&apply_defines(s, &opts.defines), &mut context, false)
+        .map_err(UpdateChecksumError::Parse)?;
+
+    Ok(context)
+}
+
+/// Returns the existing checksums of a spec re-rendered with the formatting of
+/// `opts`, without downloading anything.
+pub fn normalize_from_str(
+    s: &str,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError> {
+    let old_checksums = collect_checksums(&parse_spec(s, opts)?);
+
+    let checksums = old_checksums
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .iter()
+                .map(|x| checksum::with_algo_case(x, opts.uppercase_algo))
+                .collect();
+
+            (k.clone(), opts.skip_style.apply(v))
+        })
+        .collect();
+
+    Ok(UpdateChecksumResult {
+        checksums,
+        old_checksums,
+        ..Default::default()
+    })
+}
+
+/// Computes the checksums of a spec with the default options, downloading with
+/// `threads` concurrent downloads, see [`update_from_str_with_options`].
+pub async fn update_from_str<C>(
+    s: &str,
+    cb: C,
+    threads: usize,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    let opts = UpdateChecksumOptions {
+        threads,
+        ..Default::default()
+    };
+
+    update_from_str_with_options(s, cb, &opts).await
+}
+
+/// Computes the checksums of a spec, see [`UpdateChecksumOptions`].
+pub async fn update_from_str_with_options<C>(
+    s: &str,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    update_from_str_with_client(&build_client(opts)?, s, cb, opts).await
+}
+
+/// Like [`update_from_str_with_options`], downloading with `client`, e.g. to share its
+/// connection pool or to set up TLS, cookies or authentication.
+///
+/// The `timeout` and `proxy` of `opts` only apply to the default client, and are
+/// left to `client` here.
+pub async fn update_from_str_with_client<C>(
+    client: &Client,
+    s: &str,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    let mut context = parse_spec(s, opts)?;
+
+    let old_checksums = collect_checksums(&context);
+    let mut res = update_all_checksum(client, &mut context, cb, opts).await?;
+
+    res.checksums = collect_checksums(&context);
+    res.old_checksums = old_checksums;
+
+    let moved = res
+        .skip_positions_changed()
+        .into_iter()
+        .filter(|k| !res.repaired.iter().any(|x| x == k))
+        .map(|k| {
+            format!(
+                "SKIP positions of {k} changed, SRCS was probably reordered, please double-check"
+            )
+        })
+        .collect::<Vec<_>>();
+    for msg in moved {
+        res.warn(msg);
+    }
+    res.check_strict(opts)?;
+
+    Ok(res)
+}
+
+fn collect_checksums(context: &HashMap<String, String>) -> HashMap<String, Vec<String>> {
+    let mut checksums = HashMap::new();
+
+    for (k, v) in context {
+        if is_chksums_key(k) {
+            let v = v
+                .split_whitespace()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>();
+
+            checksums.insert(k.to_string(), v);
+        }
+    }
+
+    checksums
+}
+
+/// Writes the new checksums of a spec to it with the default options, see
+/// [`update_from_str`].
+pub async fn get_new_spec<C>(
+    spec_inner: &mut String,
+    cb: C,
+    threads: usize,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    let opts = UpdateChecksumOptions {
+        threads,
+        ..Default::default()
+    };
+
+    get_new_spec_with_options(spec_inner, cb, &opts).await
+}
+
+/// Writes the new checksums of a spec to it, see [`update_from_str_with_options`].
+pub async fn get_new_spec_with_options<C>(
+    spec_inner: &mut String,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    if opts.normalize_only {
+        return finish_spec(spec_inner, normalize_from_str(spec_inner, opts)?, opts);
+    }
+
+    get_new_spec_with_client(&build_client(opts)?, spec_inner, cb, opts).await
+}
+
+/// Like [`get_new_spec_with_options`], downloading with `client`, see
+/// [`update_from_str_with_client`].
+pub async fn get_new_spec_with_client<C>(
+    client: &Client,
+    spec_inner: &mut String,
+    cb: C,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError>
+where
+    C: Fn(Event) + Copy,
+{
+    let res = if opts.normalize_only {
+        normalize_from_str(spec_inner, opts)?
+    } else {
+        update_from_str_with_client(client, &*spec_inner, cb, opts).await?
+    };
+
+    finish_spec(spec_inner, res, opts)
+}
+
+/// Writes the checksums of `res` to `spec_inner`.
+fn finish_spec(
+    spec_inner: &mut String,
+    res: UpdateChecksumResult,
+    opts: &UpdateChecksumOptions,
+) -> Result<UpdateChecksumResult, UpdateChecksumError> {
+    debug!("{:?}", res.checksums);
+
+    update_spec_inner(res.checksums.clone(), spec_inner, opts)?;
+    update_spec_inner(res.sizes.clone(), spec_inner, opts)?;
+
+    if let Some(stamp) = &opts.stamp {
+        update_stamp(spec_inner, stamp);
+    }
+
+    if opts.validate_output {
+        validate_spec(spec_inner, opts.inherit.as_deref())
+            .wrap_err("Rewritten spec no longer parses, refusing to use it")?;
+    }
+
+    Ok(res)
+}
+
+fn validate_spec(spec: &str, inherit: Option<&str>) -> Result<(), ParseErrors> {
+    let mut context = HashMap::new();
+    if let Some(base) = inherit {
+        parse_from_str(base, &mut context, false)?;
+    }
+
+    parse_from_str(spec, &mut context, false)
+}
+
+/// Returns the offsets of the lines of `s`, except those continuing a quoted
+/// value, which may contain escaped quotes or text looking like an assignment.
+fn line_starts(s: &str) -> impl Iterator<Item = usize> + '_ {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = false;
+    let mut prev = '\n';
+
+    let starts = s.char_indices().filter_map(move |(i, c)| {
+        let was_escaped = std::mem::take(&mut escaped);
+        let after_space = prev.is_whitespace();
+        prev = c;
+
+        match c {
+            '\n' => {
+                comment = false;
+                return (!quoted).then_some(i + 1);
+            }
+            _ if comment || was_escaped => {}
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted && after_space => comment = true,
+            _ => {}
+        }
+
+        None
+    });
+
+    std::iter::once(0).chain(starts)
+}
+
+/// Returns the end of the `NAME=value` assignment starting at `start`, `None`
+/// if its quote is never closed.
+fn assignment_end(s: &str, start: usize) -> Option<usize> {
+    let value_start = start + s[start..].find('=').map(|x| x + 1).unwrap_or(0);
+    let rest = &s[value_start..];
+
+    let Some(quoted) = rest.strip_prefix('"') else {
+        return Some(value_start + rest.find('\n').unwrap_or(rest.len()));
+    };
+
+    let mut escaped = false;
+    for (i, c) in quoted.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(value_start + 1 + i + 1),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+/// Overrides the assignments of `defines` in `spec`, adding the missing ones at the top.
+pub fn apply_defines(spec: &str, defines: &[(String, String)]) -> String {
+    let mut spec = spec.to_string();
+
+    for (k, v) in defines {
+        let line = format!("{k}=\"{v}\"");
+        let prefix = format!("{k}=");
+
+        match line_starts(&spec).find(|&i| spec[i..].starts_with(&prefix)) {
+            Some(start) => {
+                let end = assignment_end(&spec, start).unwrap_or(spec.len());
+                spec.replace_range(start..end, &line);
+            }
+            None => spec.insert_str(0, &format!("{line}\n")),
+        }
+    }
+
+    spec
+}
+
+/// Formats a checksum variable the way it is written into specs.
+pub fn format_checksums(k: &str, v: &[String]) -> String {
+    format_indented(k, v, &" ".repeat(DEFAULT_INDENT))
+}
+
+fn format_indented(k: &str, v: &[String], indent: &str) -> String {
+    format!("{k}=\"{}\"", v.join(&format!(" \\\n{indent}")))
+}
+
+/// Formats a checksum variable in the layout of its `original` assignment, if
+/// any: with its continuation indentation, or on one line if it was on one line
+/// and [`UpdateChecksumOptions::keep_single_line`] is set.
+fn format_checksums_like(
+    k: &str,
+    v: &[String],
+    original: Option<&str>,
+    opts: &UpdateChecksumOptions,
+) -> String {
+    match original.map(|x| x.split_once("\\\n")) {
+        Some(Some((_, next))) => {
+            let indent = next.len() - next.trim_start_matches([' ', '\t']).len();
+            format_indented(k, v, &next[..indent])
+        }
+        Some(None) if opts.keep_single_line => format!("{k}=\"{}\"", v.join(" ")),
+        _ => format_indented(k, v, &" ".repeat(opts.indent)),
+    }
+}
+
+/// Updates the provenance comment in place, or inserts it above the first `CHKSUMS`.
+fn update_stamp(spec_inner: &mut String, stamp: &str) {
+    let line = format!("{STAMP_PREFIX}{stamp}");
+
+    if let Some(start) =
+        line_starts(spec_inner).find(|&i| spec_inner[i..].starts_with(STAMP_PREFIX))
+    {
+        let end = spec_inner[start..]
+            .find('\n')
+            .map(|x| start + x)
+            .unwrap_or(spec_inner.len());
+        spec_inner.replace_range(start..end, &line);
+    } else if let Some(start) =
+        line_starts(spec_inner).find(|&i| spec_inner[i..].starts_with("CHKSUMS"))
+    {
+        spec_inner.insert_str(start, &format!("{line}\n"));
+    }
+}
+
+/// Rewrites the checksum variables of `new` in place.
+///
+/// An empty list removes the variable, and a variable missing from the spec is
+/// inserted after its `SRCS`, or a `CHKSIZE` after its `CHKSUMS`. Rewritten
+/// variables keep their layout, see [`format_checksums_like`]. The rest of the
+/// spec is left byte for byte, including the lack of a final newline.
+fn update_spec_inner(
+    new: HashMap<String, Vec<String>>,
+    spec_inner: &mut String,
+    opts: &UpdateChecksumOptions,
+) -> Result<()> {
+    let final_newline = spec_inner.is_empty() || spec_inner.ends_with('\n');
+
+    for (k, v) in new {
+        // anchored, `CHKSUMS` must not be found inside `CHKSUMS__AMD64`
+        let prefix = format!("{k}=");
+        let start = line_starts(spec_inner).find(|&i| spec_inner[i..].starts_with(&prefix));
+
+        match start {
+            Some(start) => {
+                let mut end = assignment_end(spec_inner, start)
+                    .with_context(|| format!("Unterminated quote in the value of {k}"))?;
+                debug!("replace range: {}", &spec_inner[start..end]);
+
+                if v.is_empty() {
+                    if spec_inner[end..].starts_with('\n') {
+                        end += 1;
+                    }
+                    spec_inner.replace_range(start..end, "");
+                } else {
+                    let original = &spec_inner[start..end];
+                    let line = format_checksums_like(&k, &v, Some(original), opts);
+                    spec_inner.replace_range(start..end, &line);
+                }
+            }
+            None if v.is_empty() => {}
+            None => {
+                let srcs_key = match k.strip_prefix("CHKSIZE") {
+                    Some(arch) => format!("CHKSUMS{arch}"),
+                    None => k.replacen("CHKSUMS", "SRCS", 1),
+                };
+                let srcs = format!("{srcs_key}=");
+                let line = format!("{}\n", format_checksums_like(&k, &v, None, opts));
+
+                match line_starts(spec_inner).find(|&i| spec_inner[i..].starts_with(&srcs)) {
+                    Some(start) => {
+                        let mut end = assignment_end(spec_inner, start).with_context(|| {
+                            format!("Unterminated quote in the value of {srcs_key}")
+                        })?;
+                        if spec_inner[end..].starts_with('\n') {
+                            end += 1;
+                        } else {
+                            spec_inner.insert(end, '\n');
+                            end += 1;
+                        }
+                        spec_inner.insert_str(end, &line);
+                    }
+                    None => {
+                        if !spec_inner.is_empty() && !spec_inner.ends_with('\n') {
+                            spec_inner.push('\n');
+                        }
+                        spec_inner.push_str(&line);
+                    }
+                }
+            }
+        }
+    }
+
+    // a variable removed or appended last must not add or drop the final newline
+    if !final_newline && spec_inner.ends_with('\n') {
+        spec_inner.pop();
+    } else if final_newline && !spec_inner.is_empty() && !spec_inner.ends_with('\n') {
+        spec_inner.push('\n');
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_update_spec() {
+    let map1 = [("CHKSUMS".to_string(), vec!["sha256::xyz".to_string()])]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let mut spec = r#"VER=5.115.0
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::abc \
+         SKIP"
+CHKUPDATE="anitya::id=8762""#
+        .to_string();
+
+    update_spec_inner(map1, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+
+    assert_eq!(
+        spec,
+        r#"VER=5.115.0
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::xyz"
+CHKUPDATE="anitya::id=8762""#
+            .to_string()
+    );
+
+    let map1 = [("CHKSUMS".to_string(), vec!["sha256::xyz".to_string()])]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let mut spec = r#"VER=5.115.0
 SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
 CHKSUMS="sha256::abc"
 CHKUPDATE="anitya::id=8762""#
         .to_string();
 
-    update_spec_inner(map1, &mut spec);
+    update_spec_inner(map1, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+
+    assert_eq!(
+        spec,
+        r#"VER=5.115.0
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::xyz"
+CHKUPDATE="anitya::id=8762""#
+            .to_string()
+    );
+
+    let map2 = [(
+        "CHKSUMS".to_string(),
+        vec![
+            "SKIP".to_string(),
+            "sha256::95ecac13c3f8c69e3e80fa73864102f13730b5fd87e7438c23b96766ef458e41".to_string(),
+            "sha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb".to_string(),
+        ],
+    )]
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    let mut spec = r#"VER=3.113
+SRCS="git::commit=tags/v$VER::https://github.com/lxgw/kose-font \
+      file::rename=XiaolaiMonoSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiMonoSC-Regular.ttf \
+      file::rename=XiaolaiSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiSC-Regular.ttf"
+CHKSUMS="SKIP \
+         sha256::95ecac13c3f8csha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb73864102f13730b5fd87e7438c23b96766ef458e41 \
+         sha256::x"
+CHKUPDATE="anitya::id=374941""#.to_string();
+
+    update_spec_inner(map2, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+
+    assert_eq!(
+        spec,
+r#"VER=3.113
+SRCS="git::commit=tags/v$VER::https://github.com/lxgw/kose-font \
+      file::rename=XiaolaiMonoSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiMonoSC-Regular.ttf \
+      file::rename=XiaolaiSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiSC-Regular.ttf"
+CHKSUMS="SKIP \
+         sha256::95ecac13c3f8c69e3e80fa73864102f13730b5fd87e7438c23b96766ef458e41 \
+         sha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb"
+CHKUPDATE="anitya::id=374941""#.to_string()
+    );
+}
+
+#[test]
+fn test_update_spec_layout() {
+    let new = |v: &[&str]| {
+        HashMap::from([(
+            "CHKSUMS".to_string(),
+            v.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+        )])
+    };
+
+    let defaults = UpdateChecksumOptions::default();
+
+    // an indentation of its own round-trips unchanged
+    let orig = "SRCS=\"a b\"\nCHKSUMS=\"sha256::abc \\\n    SKIP\"\n";
+    let mut spec = orig.to_string();
+    update_spec_inner(new(&["sha256::abc", "SKIP"]), &mut spec, &defaults).unwrap();
+    assert_eq!(spec, orig);
+    update_spec_inner(new(&["sha256::def", "SKIP"]), &mut spec, &defaults).unwrap();
+    assert_eq!(spec, "SRCS=\"a b\"\nCHKSUMS=\"sha256::def \\\n    SKIP\"\n");
+
+    let single_line = UpdateChecksumOptions {
+        keep_single_line: true,
+        ..Default::default()
+    };
+    let mut spec = "SRCS=\"a b\"\nCHKSUMS=\"SKIP SKIP\"\n".to_string();
+    update_spec_inner(new(&["sha256::abc", "SKIP"]), &mut spec, &single_line).unwrap();
+    assert_eq!(spec, "SRCS=\"a b\"\nCHKSUMS=\"sha256::abc SKIP\"\n");
+
+    // without a continuation to follow, `indent` is used
+    let indent = UpdateChecksumOptions {
+        indent: 4,
+        ..Default::default()
+    };
+    update_spec_inner(new(&["sha256::abc", "SKIP"]), &mut spec, &indent).unwrap();
+    assert_eq!(spec, "SRCS=\"a b\"\nCHKSUMS=\"sha256::abc \\\n    SKIP\"\n");
+}
+
+#[test]
+fn test_update_spec_round_trip() {
+    let orig = include_str!("../testdata/kiconthemes.spec");
+    let old = "sha256::0000000000000000000000000000000000000000000000000000000000000000";
+    let old_loongson3 = "sha256::1111111111111111111111111111111111111111111111111111111111111111";
+    let new = HashMap::from([
+        (
+            "CHKSUMS".to_string(),
+            vec!["sha256::abc".to_string(), "SKIP".to_string()],
+        ),
+        (
+            "CHKSUMS__LOONGSON3".to_string(),
+            vec!["sha256::def".to_string()],
+        ),
+    ]);
+
+    for orig in [orig, orig.trim_end_matches('\n')] {
+        let expected = orig
+            .replace(old, "sha256::abc")
+            .replace(old_loongson3, "sha256::def");
+        let mut spec = orig.to_string();
+        update_spec_inner(new.clone(), &mut spec, &UpdateChecksumOptions::default()).unwrap();
+        assert_eq!(spec, expected);
+
+        // only the intended bytes change when nothing is new
+        let mut spec = orig.to_string();
+        let same = collect_checksums(&parse_spec(orig, &UpdateChecksumOptions::default()).unwrap());
+        update_spec_inner(same, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+        assert_eq!(spec, orig);
+    }
+
+    // removed or appended last, without a final newline to begin with
+    let mut spec = "SRCS=\"a\"\nCHKSUMS=\"SKIP\"".to_string();
+    let removed = HashMap::from([("CHKSUMS".to_string(), vec![])]);
+    update_spec_inner(removed, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(spec, "SRCS=\"a\"");
+    let appended = HashMap::from([("CHKSUMS".to_string(), vec!["SKIP".to_string()])]);
+    update_spec_inner(appended, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(spec, "SRCS=\"a\"\nCHKSUMS=\"SKIP\"");
+}
+
+#[test]
+fn test_update_spec_arch_prefix() {
+    let new = [
+        ("CHKSUMS".to_string(), vec!["sha256::new".to_string()]),
+        (
+            "CHKSUMS__AMD64".to_string(),
+            vec!["sha256::new-amd64".to_string()],
+        ),
+    ]
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    // `CHKSUMS` must not be found inside `CHKSUMS__AMD64`, whichever comes first
+    let mut spec = "CHKSUMS__AMD64=\"sha256::old-amd64\"\nCHKSUMS=\"sha256::old\"\n".to_string();
+    update_spec_inner(new.clone(), &mut spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        spec,
+        "CHKSUMS__AMD64=\"sha256::new-amd64\"\nCHKSUMS=\"sha256::new\"\n"
+    );
+
+    let mut spec = "CHKSUMS=\"sha256::old\"\nCHKSUMS__AMD64=\"sha256::old-amd64\"\n".to_string();
+    update_spec_inner(new, &mut spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        spec,
+        "CHKSUMS=\"sha256::new\"\nCHKSUMS__AMD64=\"sha256::new-amd64\"\n"
+    );
+}
+
+#[test]
+fn test_update_spec_missing_key() {
+    let new = [
+        ("CHKSUMS".to_string(), vec!["SKIP".to_string()]),
+        (
+            "CHKSUMS__AMD64".to_string(),
+            vec!["sha256::abc".to_string()],
+        ),
+    ]
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    let mut spec = r#"VER=1.0
+SRCS="git::commit=tags/v$VER::https://example.org/foo.git"
+SRCS__AMD64="tbl::https://example.org/foo-amd64-$VER.tar.xz"
+CHKUPDATE="anitya::id=1"
+"#
+    .to_string();
+    update_spec_inner(new.clone(), &mut spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        spec,
+        r#"VER=1.0
+SRCS="git::commit=tags/v$VER::https://example.org/foo.git"
+CHKSUMS="SKIP"
+SRCS__AMD64="tbl::https://example.org/foo-amd64-$VER.tar.xz"
+CHKSUMS__AMD64="sha256::abc"
+CHKUPDATE="anitya::id=1"
+"#
+    );
+
+    let mut spec = "SRCS=\"git::commit=tags/v1.0::https://example.org/foo.git\n".to_string();
+    assert!(update_spec_inner(new, &mut spec, &UpdateChecksumOptions::default()).is_err());
+}
+
+#[test]
+fn test_update_stamp() {
+    let mut spec = r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.xz"
+CHKSUMS="sha256::abc""#
+        .to_string();
+
+    update_stamp(
+        &mut spec,
+        "abbs-update-checksum v0.3.0 on 2024-01-01T00:00:00Z",
+    );
+    update_stamp(
+        &mut spec,
+        "abbs-update-checksum v0.3.0 on 2024-02-01T00:00:00Z",
+    );
+
+    assert_eq!(
+        spec,
+        r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.xz"
+# checksums updated by abbs-update-checksum v0.3.0 on 2024-02-01T00:00:00Z
+CHKSUMS="sha256::abc""#
+    );
+}
+
+#[test]
+fn test_options_builder() {
+    let opts = UpdateChecksumOptions::builder()
+        .threads(8)
+        .timeout(Duration::from_secs(30))
+        .keep_going(true)
+        .build();
+
+    assert_eq!(opts.threads, 8);
+    assert_eq!(opts.timeout, Some(Duration::from_secs(30)));
+    assert!(opts.keep_going);
+    // the others keep their default
+    assert_eq!(opts.indent, DEFAULT_INDENT);
+    assert!(opts.check_length);
+}
+
+#[test]
+fn test_source_from_json() {
+    let sources: Vec<Source> = serde_json::from_str(
+        r#"[
+            {"url": "https://example.org/foo.tar.xz"},
+            {"type": "git", "options": ["commit=tags/v1.0"], "url": "https://example.org/foo", "arch": "amd64"},
+            {"type": "pypi", "options": ["version=1.0"], "url": "foo"}
+        ]"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        sources.iter().map(|x| x.to_token()).collect::<Vec<_>>(),
+        vec![
+            "tbl::https://example.org/foo.tar.xz",
+            "git::commit=tags/v1.0::https://example.org/foo",
+            "pypi::version=1.0::foo",
+        ]
+    );
+    assert_eq!(sources[1].arch.as_deref(), Some("amd64"));
+    assert_eq!(sources[0].arch, None);
+}
+
+#[test]
+fn test_ignore_list() {
+    let ignore = IgnoreList::parse(
+        r#"# comment
+
+https://example.org/nightly/*
+foo* https://example.org/foo-*.tar.gz
+"#,
+    )
+    .unwrap();
+
+    assert!(ignore.matches("https://example.org/nightly/bar.tar.gz"));
+    assert!(!ignore.matches("https://example.org/foo-1.0.tar.gz"));
+
+    let foo = ignore.for_package("foobar");
+    assert!(foo.matches("https://example.org/nightly/bar.tar.gz"));
+    assert!(foo.matches("https://example.org/foo-1.0.tar.gz"));
+
+    let bar = ignore.for_package("bar");
+    assert!(!bar.matches("https://example.org/foo-1.0.tar.gz"));
+
+    assert!(IgnoreList::parse("a b c").is_err());
+}
+
+#[test]
+fn test_apply_defines() {
+    let spec = r#"VER=5.115.0
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::abc""#;
+
+    assert_eq!(
+        apply_defines(
+            spec,
+            &[
+                ("VER".to_string(), "5.116.0".to_string()),
+                ("REL".to_string(), "1".to_string())
+            ]
+        ),
+        r#"REL="1"
+VER="5.116.0"
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::abc""#
+    );
+}
+
+#[test]
+fn test_skip_positions_changed() {
+    let to_vec = |v: &[&str]| v.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    let res = UpdateChecksumResult {
+        checksums: [
+            ("CHKSUMS".to_string(), to_vec(&["sha256::a", "SKIP"])),
+            ("CHKSUMS__amd64".to_string(), to_vec(&["SKIP", "sha256::c"])),
+        ]
+        .into_iter()
+        .collect(),
+        old_checksums: [
+            ("CHKSUMS".to_string(), to_vec(&["SKIP", "sha256::b"])),
+            ("CHKSUMS__amd64".to_string(), to_vec(&["SKIP", "sha256::d"])),
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+
+    assert_eq!(res.skip_positions_changed(), vec!["CHKSUMS"]);
+}
+
+#[test]
+fn test_expand_vars() {
+    let context = [
+        ("NAME", "foo"),
+        ("VER", "1.2"),
+        ("SRCTBL", "https://example.org/$NAME-${VER}.tar.gz"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect::<HashMap<_, _>>();
+
+    assert_eq!(
+        expand_vars("tbl::$SRCTBL", &context),
+        "tbl::https://example.org/foo-1.2.tar.gz"
+    );
+    assert_eq!(
+        expand_vars("https://example.org/${NAME}/$NAME-$VER.tar.gz", &context),
+        "https://example.org/foo/foo-1.2.tar.gz"
+    );
+    assert_eq!(expand_vars("$UNKNOWN-${VER", &context), "$UNKNOWN-${VER");
+}
+
+#[tokio::test]
+async fn test_archs() {
+    let addr = serve(&[
+        ("GET /foo.tar.gz", "hello world"),
+        ("GET /bar.bin", "hello world"),
+    ])
+    .await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="sha256::abc"
+SRCS__AMD64="tbl::http://{addr}/bar.bin"
+CHKSUMS__AMD64="sha256::abc"
+"#
+    );
+    let update = |archs: &[&str]| {
+        let opts = UpdateChecksumOptions {
+            archs: archs.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        let spec = spec.clone();
+        async move {
+            update_from_str_with_options(&spec, |_| {}, &opts)
+                .await
+                .unwrap()
+                .checksums
+        }
+    };
+
+    let checksums = update(&["amd64"]).await;
+    assert_eq!(checksums["CHKSUMS"], ["sha256::abc"]);
+    assert_eq!(checksums["CHKSUMS__AMD64"], [HELLO_SHA256]);
+
+    // built from SRCS
+    let checksums = update(&["arm64"]).await;
+    assert_eq!(checksums["CHKSUMS"], [HELLO_SHA256]);
+    assert_eq!(checksums["CHKSUMS__AMD64"], ["sha256::abc"]);
+
+    let checksums = update(&[]).await;
+    assert_eq!(checksums["CHKSUMS"], [HELLO_SHA256]);
+    assert_eq!(checksums["CHKSUMS__AMD64"], [HELLO_SHA256]);
+}
+
+#[test]
+fn test_expand_trim() {
+    let context = [("VER", "5.116.0"), ("PAT", ".*")]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    assert_eq!(expand_vars("${VER%.*}", &context), "5.116");
+    assert_eq!(expand_vars("${VER%%.*}", &context), "5");
+    assert_eq!(expand_vars("${VER#*.}", &context), "116.0");
+    assert_eq!(expand_vars("${VER##*.}", &context), "0");
+    assert_eq!(expand_vars("${VER%$PAT}", &context), "5.116");
+    assert_eq!(expand_vars("${VER%-*}", &context), "5.116.0");
+    assert_eq!(expand_vars("${UNKNOWN%.*}", &context), "${UNKNOWN%.*}");
+
+    let spec = r#"VER=5.116.0
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
+CHKSUMS="sha256::abc"
+"#;
+    let sources = list_sources(spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        sources[0].url,
+        "https://download.kde.org/stable/frameworks/5.116/kiconthemes-5.116.0.tar.xz"
+    );
+}
+
+#[test]
+fn test_source_algo() {
+    let srcs = "tbl::https://example.org/a.tar.gz \
+                tbl::algo=sha512::https://example.org/b.tar.gz \
+                file::rename=c::algo=SHA256::https://example.org/c";
+
+    let algos = srcs
+        .split_whitespace()
+        .map(|x| SrcToken::parse(x).algo(ChecksumAlgo::Sha256).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        algos,
+        [
+            ChecksumAlgo::Sha256,
+            ChecksumAlgo::Sha512,
+            ChecksumAlgo::Sha256
+        ]
+    );
+    assert!(SrcToken::parse("tbl::algo=md4::https://example.org/d")
+        .algo(ChecksumAlgo::Sha256)
+        .is_err());
+}
+
+#[test]
+fn test_skip_style() {
+    let skips = vec!["SKIP".to_string(), "SKIP".to_string()];
+    let mixed = vec!["SKIP".to_string(), "sha256::abc".to_string()];
+
+    assert_eq!(SkipStyle::Full.apply(skips.clone()), skips);
+    assert_eq!(SkipStyle::Single.apply(skips.clone()), ["SKIP"]);
+    assert!(SkipStyle::Omit.apply(skips.clone()).is_empty());
+    assert_eq!(SkipStyle::Omit.apply(mixed.clone()), mixed);
+
+    let spec = r#"VER=1.0
+SRCS="git::commit=tags/v$VER::https://example.org/foo.git \
+      git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="SKIP \
+         SKIP"
+CHKUPDATE="anitya::id=1"
+"#;
+
+    let mut omitted = spec.to_string();
+    update_spec_inner(
+        [("CHKSUMS".to_string(), vec![])].into_iter().collect(),
+        &mut omitted,
+        &UpdateChecksumOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        omitted,
+        r#"VER=1.0
+SRCS="git::commit=tags/v$VER::https://example.org/foo.git \
+      git::commit=tags/v$VER::https://example.org/bar.git"
+CHKUPDATE="anitya::id=1"
+"#
+    );
+
+    update_spec_inner(
+        [("CHKSUMS".to_string(), vec!["SKIP".to_string()])]
+            .into_iter()
+            .collect(),
+        &mut omitted,
+        &UpdateChecksumOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        omitted,
+        r#"VER=1.0
+SRCS="git::commit=tags/v$VER::https://example.org/foo.git \
+      git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="SKIP"
+CHKUPDATE="anitya::id=1"
+"#
+    );
+}
+
+#[tokio::test]
+async fn test_inherit() {
+    let spec = r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.gz \
+      tbl::https://example.org/foo-data-$VER.tar.gz"
+CHKSUMS="sha256::abc \
+         sha256::def"
+"#;
+    let defines = r#"PKGNAME=foo-doc
+SRCS="tbl::https://example.org/foo-doc-$VER.tar.gz"
+CHKSUMS="SKIP"
+"#;
+
+    let opts = UpdateChecksumOptions {
+        inherit: Some(spec.to_string()),
+        ignore: IgnoreList::parse("https://example.org/foo-doc-1.0.tar.gz").unwrap(),
+        ..Default::default()
+    };
+    let res = update_from_str_with_options(defines, |_| {}, &opts)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        res.checksums,
+        [("CHKSUMS".to_string(), vec!["SKIP".to_string()])]
+            .into_iter()
+            .collect()
+    );
+}
+
+#[test]
+fn test_validate_spec() {
+    assert!(validate_spec("VER=1.0\nCHKSUMS=\"sha256::abc\"\n", None).is_ok());
+    assert!(validate_spec("VER=1.0\nCHKSUMS=\"sha256::abc\n", None).is_err());
+}
+
+#[tokio::test]
+async fn test_group_error() {
+    let spec = r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git \
+      tbl::http://127.0.0.1:1/foo-data-1.0.tar.gz"
+CHKSUMS="SKIP \
+         sha256::abc"
+"#;
+
+    let e = update_from_str_with_options(spec, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap_err();
+    let UpdateChecksumError::Group(e) = e else {
+        panic!("not a group error");
+    };
+
+    assert_eq!(e.key, "CHKSUMS");
+    assert_eq!(e.checksums, [Some("SKIP".to_string()), None]);
+    assert_eq!(e.failures[0].0, "http://127.0.0.1:1/foo-data-1.0.tar.gz");
+}
+
+#[tokio::test]
+async fn test_local_source() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("foo.tar.gz"), "hello world").unwrap();
+    let opts = UpdateChecksumOptions {
+        base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+
+    let sources = [
+        tbl("foo.tar.gz"),
+        tbl(format!(
+            "file://{}",
+            dir.path().join("foo.tar.gz").display()
+        )),
+    ];
+    let res = update_from_sources(&sources, |_| {}, &opts).await.unwrap();
+    for src in res {
+        assert_eq!(src.checksum, HELLO_SHA256);
+        assert_eq!(src.size, Some(11));
+    }
+
+    assert!(update_from_sources(&[tbl("bar.tar.gz")], |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_progress_url() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz?token=secret"))];
+    let opts = UpdateChecksumOptions {
+        strip_query: true,
+        ..Default::default()
+    };
+
+    let urls = std::sync::Mutex::new(vec![]);
+    let urls_ref = &urls;
+    update_from_sources(
+        &sources,
+        |event| {
+            if let Event::Downloading { url, .. } = event {
+                urls_ref.lock().unwrap().push(url.to_string());
+            }
+        },
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        urls.into_inner().unwrap(),
+        [format!("http://{addr}/foo.tar.gz")]
+    );
+}
+
+#[test]
+fn test_crates_source() {
+    let spec = r#"VER=1.0.3
+SRCS="crates::version=$VER::foo-bar"
+CHKSUMS="sha256::abc"
+"#;
+
+    let sources = list_sources(spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        sources[0].url,
+        "https://static.crates.io/crates/foo-bar/foo-bar-1.0.3.crate"
+    );
+
+    assert!(list_sources(
+        "SRCS=\"crates::foo-bar\"\n",
+        &UpdateChecksumOptions::default()
+    )
+    .is_err());
+}
+
+#[test]
+fn test_pypi_source() {
+    let spec = r#"VER=1.0
+SRCS="pypi::version=$VER::foo-bar \
+      pypi::version=$VER::type=wheel::foo-bar \
+      pypi::version=$VER::type=wheel::tag=cp312-cp312-manylinux_2_17_x86_64::foo-bar"
+CHKSUMS="sha256::abc sha256::abc sha256::abc"
+"#;
+
+    let sources = list_sources(spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        sources.iter().map(|x| x.url.as_str()).collect::<Vec<_>>(),
+        [
+            "https://pypi.io/packages/source/f/foo-bar/foo-bar-1.0.tar.gz",
+            // e.g. of a package publishing no sdist
+            "https://pypi.io/packages/py3/f/foo-bar/foo_bar-1.0-py3-none-any.whl",
+            "https://pypi.io/packages/cp312/f/foo-bar/\
+             foo_bar-1.0-cp312-cp312-manylinux_2_17_x86_64.whl",
+        ]
+    );
+
+    for srcs in [
+        "pypi::version=1.0::type=egg::foo",
+        "pypi::version=1.0::tag=py3-none-any::foo",
+        "pypi::version=1.0::type=wheel::tag=py3::foo",
+    ] {
+        let e = list_sources(
+            &format!("SRCS=\"{srcs}\"\n"),
+            &UpdateChecksumOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.class(),
+            UpdateChecksumError::PypiDistribution { pkg } if pkg == "foo"
+        ));
+    }
+}
+
+#[test]
+fn test_latest_pypi_version() {
+    let project = serde_json::from_str::<PypiProject>(
+        r#"{"releases": {
+            "1.9": [{"packagetype": "sdist"}],
+            "1.10": [{"packagetype": "sdist"}, {"packagetype": "bdist_wheel"}],
+            "1.11": [{"packagetype": "sdist", "yanked": true}],
+            "1.12": [{"packagetype": "bdist_wheel"}],
+            "2.0rc1": [{"packagetype": "sdist"}],
+            "2.0.dev3": [{"packagetype": "sdist"}],
+            "1.10.post1": [{"packagetype": "sdist"}]
+        }}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        latest_pypi_version(&project, false, false),
+        Some("1.10.post1")
+    );
+    assert_eq!(latest_pypi_version(&project, true, false), Some("1.12"));
+    assert_eq!(latest_pypi_version(&project, false, true), Some("2.0rc1"));
+
+    assert!(pypi_version_key("2.0.dev3") < pypi_version_key("2.0a1"));
+    assert!(pypi_version_key("2.0b2") < pypi_version_key("2.0rc1"));
+    assert!(pypi_version_key("2.0rc1") < pypi_version_key("2.0"));
+    assert_eq!(pypi_version_key("1.0+local"), None);
+}
+
+#[test]
+fn test_github_source() {
+    let spec = r#"VER=1.0
+SRCS="github::tag=v$VER::foo/bar \
+      github::tag=v$VER::asset=bar-$VER.tar.xz::foo/bar \
+      github::ref=0123abc::foo/bar"
+CHKSUMS="sha256::abc sha256::abc sha256::abc"
+"#;
+
+    let sources = list_sources(spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        sources.iter().map(|x| x.url.as_str()).collect::<Vec<_>>(),
+        [
+            "https://codeload.github.com/foo/bar/tar.gz/refs/tags/v1.0",
+            "https://github.com/foo/bar/releases/download/v1.0/bar-1.0.tar.xz",
+            "https://codeload.github.com/foo/bar/tar.gz/0123abc",
+        ]
+    );
+
+    for srcs in [
+        "github::foo/bar",
+        "github::tag=v1.0::bar",
+        "github::tag=v1.0::ref=main::foo/bar",
+    ] {
+        let e = list_sources(
+            &format!("SRCS=\"{srcs}\"\n"),
+            &UpdateChecksumOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.class(),
+            UpdateChecksumError::GithubResolution { .. }
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_error_classes() {
+    let addr = serve(&[]).await;
+    let opts = UpdateChecksumOptions::default();
+
+    let e = update_from_str_with_options(
+        &format!("SRCS=\"tbl::http://{addr}/foo.tar.gz\"\n"),
+        |_| {},
+        &opts,
+    )
+    .await
+    .unwrap_err();
+    // returned as the group that failed
+    assert!(matches!(e, UpdateChecksumError::Group(_)));
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::HttpStatus { url, status }
+            if *url == format!("http://{addr}/foo.tar.gz") && *status == StatusCode::NOT_FOUND
+    ));
+
+    let e = update_from_str_with_options("SRCS=\"pypi::foo\"\n", |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::PypiResolution { pkg, ver: None } if pkg == "foo"
+    ));
+
+    let e = update_from_str_with_options("SRCS=\"foo\n", |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(matches!(e.class(), UpdateChecksumError::Parse(_)));
+}
+
+#[tokio::test]
+async fn test_keep_going() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz tbl::http://{addr}/bar.tar.gz"
+CHKSUMS="sha256::0 sha256::abc"
+SRCS__AMD64="tbl::http://{addr}/baz.tar.gz"
+"#
+    );
+    let opts = UpdateChecksumOptions {
+        keep_going: true,
+        ..Default::default()
+    };
+
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256, "sha256::abc"]);
+    assert_eq!(res.checksums["CHKSUMS__AMD64"], ["SKIP"]);
+
+    let mut failed = res
+        .failed_sources
+        .iter()
+        .map(|(url, _)| url.as_str())
+        .collect::<Vec<_>>();
+    failed.sort();
+    let (_, e) = res
+        .failed_sources
+        .iter()
+        .find(|(url, _)| url.ends_with("/bar.tar.gz"))
+        .unwrap();
+    assert_eq!(e.to_string(), "Source 2 of SRCS");
+    assert!(matches!(
+        UpdateChecksumError::find(e),
+        Some(UpdateChecksumError::HttpStatus { status, .. }) if *status == StatusCode::NOT_FOUND
+    ));
+    assert_eq!(
+        failed,
+        [
+            format!("http://{addr}/bar.tar.gz"),
+            format!("http://{addr}/baz.tar.gz")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_duplicate_url() {
+    // any download after the first fails
+    let (addr, _) = serve_raw(&[
+        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+        b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+    ])
+    .await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="SKIP SKIP"
+"#
+    );
+
+    let res = update_from_str(&spec, |_| {}, 4).await.unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256; 2]);
+}
+
+#[tokio::test]
+async fn test_skip_unchanged() {
+    // only answers HEAD, downloading the source fails
+    let addr = serve(&[("HEAD /foo.tar.gz", "hello world")]).await;
+    let url = format!("http://{addr}/foo.tar.gz");
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, "11".parse().unwrap());
+    headers.insert(reqwest::header::ETAG, "\"11\"".parse().unwrap());
+    let hashed = Hashed {
+        checksum: HELLO_SHA256.to_string(),
+        size: 11,
+        uncompressed_size: None,
+    };
+    let validators = SourceValidators::default();
+    validators.insert(&url, &headers, &hashed);
+    let opts = UpdateChecksumOptions {
+        validators: Some(validators.clone()),
+        ..Default::default()
+    };
+
+    let spec = format!("SRCS=\"tbl::{url}\"\nCHKSUMS=\"{HELLO_SHA256}\"\n");
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+
+    let spec = format!("SRCS=\"tbl::{url}\"\nCHKSUMS=\"sha256::abc\"\n");
+    assert!(update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .is_err());
+
+    // downloads are recorded for the next run
+    let addr = serve(&[("GET /bar.tar.gz", "hello world")]).await;
+    let url = format!("http://{addr}/bar.tar.gz");
+    let spec = format!("SRCS=\"tbl::{url}\"\nCHKSUMS=\"sha256::abc\"\n");
+    update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert!(validators
+        .unchanged(&url, ChecksumAlgo::Sha256, HELLO_SHA256, &headers)
+        .is_some());
+}
+
+/// Checksum of `hello world`, the body most tests serve.
+#[cfg(test)]
+const HELLO_SHA256: &str =
+    "sha256::b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+/// A `tbl` source without options, for any architecture.
+#[cfg(test)]
+fn tbl(url: impl Into<String>) -> Source {
+    Source {
+        typ: "tbl".to_string(),
+        options: vec![],
+        url: url.into(),
+        arch: None,
+    }
+}
+
+/// Serves the body of each `("<method> <path>", body)` route over HTTP on a local port.
+#[cfg(test)]
+async fn serve(routes: &'static [(&'static str, &'static str)]) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            respond(stream, routes).await;
+        }
+    });
+
+    addr
+}
+
+/// Answers the n-th connection on a local port with `responses[n]` as is, the
+/// last one for any later connection, and closes it. Returns the requests read,
+/// in order.
+#[cfg(test)]
+async fn serve_raw(
+    responses: &'static [&'static [u8]],
+) -> (
+    std::net::SocketAddr,
+    std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+) {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let requests_ref = requests.clone();
+    tokio::spawn(async move {
+        for n in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..len]).into_owned();
+            requests_ref.lock().unwrap().push(req);
+            let resp = responses[n.min(responses.len() - 1)];
+            stream.write_all(resp).await.unwrap();
+        }
+    });
+
+    (addr, requests)
+}
+
+/// Answers a single request read from `stream`, see [`serve`].
+#[cfg(test)]
+async fn respond<S>(mut stream: S, routes: &[(&str, &str)])
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    let req = String::from_utf8_lossy(&buf[..n]);
+    let route = req.split(' ').take(2).collect::<Vec<_>>().join(" ");
+
+    let resp = match routes.iter().find(|(x, _)| *x == route) {
+        // an `ETag` for `SourceValidators`, which needs one
+        Some((_, body)) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"{}\"\r\nConnection: close\r\n\r\n{body}",
+            body.len(),
+            body.len()
+        ),
+        None => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    stream.write_all(resp.as_bytes()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_multi_part_source() {
+    let addr = serve(&[("GET /part1", "hello "), ("GET /part2", "world")]).await;
+
+    let sources = [tbl(format!("http://{addr}/part1|http://{addr}/part2"))];
+    let res = update_from_sources(&sources, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap();
+
+    // sha256 of `hello world`
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+}
+
+#[tokio::test]
+async fn test_command_substitution() {
+    let srcs = "tbl::https://example.org/foo-$(date +%Y).tar.gz \
+                git::commit=tags/v1.0::https://example.org/foo.git";
+    assert_eq!(
+        split_srcs(srcs),
+        [
+            "tbl::https://example.org/foo-$(date +%Y).tar.gz",
+            "git::commit=tags/v1.0::https://example.org/foo.git"
+        ]
+    );
+
+    let sources = [tbl("https://example.org/foo-$(date +%Y).tar.gz")];
+    let res = update_from_sources(&sources, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(res[0].checksum, "SKIP");
+
+    let opts = UpdateChecksumOptions {
+        strict: true,
+        ..Default::default()
+    };
+    assert!(update_from_sources(&sources, |_| {}, &opts).await.is_err());
+}
+
+#[tokio::test]
+async fn test_resolve_before_download() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // the PyPI source of SRCS__amd64 fails to resolve
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo-1.0.tar.gz"
+SRCS__AMD64="pypi::foo"
+CHKSUMS="SKIP"
+CHKSUMS__AMD64="SKIP"
+"#
+    );
+
+    let res = update_from_str_with_options(&spec, |_| {}, &UpdateChecksumOptions::default()).await;
+
+    assert!(res.is_err());
+    assert_eq!(
+        listener.accept().unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    // the others are still updated
+    let addr = serve(&[("GET /foo-1.0.tar.gz", "hello world")]).await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo-1.0.tar.gz"
+SRCS__AMD64="pypi::foo"
+CHKSUMS="SKIP"
+CHKSUMS__AMD64="SKIP"
+"#
+    );
+    let opts = UpdateChecksumOptions {
+        keep_going: true,
+        ..Default::default()
+    };
+
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+    assert_eq!(
+        res.failed_groups
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>(),
+        ["CHKSUMS__AMD64"]
+    );
+
+    // as are those whose latest release could not be looked up, offline here
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("foo-1.0.tar.gz"), "hello world").unwrap();
+    let spec = r#"SRCS="tbl::foo-1.0.tar.gz"
+SRCS__AMD64="pypi::foo"
+CHKSUMS="SKIP"
+CHKSUMS__AMD64="SKIP"
+"#;
+    let opts = UpdateChecksumOptions {
+        base_dir: Some(dir.path().to_path_buf()),
+        pypi_latest: true,
+        offline: true,
+        ..opts
+    };
+
+    let res = update_from_str_with_options(spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+    let [(key, e)] = res.failed_groups.as_slice() else {
+        panic!("{:?}", res.failed_groups);
+    };
+    assert_eq!(key, "CHKSUMS__AMD64");
+    assert!(matches!(
+        UpdateChecksumError::find(e),
+        Some(UpdateChecksumError::NotStored { .. })
+    ));
+}
+
+#[test]
+fn test_recorded_url() {
+    let url = "https://example.s3.amazonaws.com/foo.tar.gz?X-Amz-Signature=abc&X-Amz-Expires=60";
+    let opts = UpdateChecksumOptions {
+        strip_query: true,
+        ..Default::default()
+    };
+
+    assert_eq!(recorded_url(url, &UpdateChecksumOptions::default()), url);
+    assert_eq!(
+        recorded_url(url, &opts),
+        "https://example.s3.amazonaws.com/foo.tar.gz"
+    );
+}
+
+#[tokio::test]
+async fn test_inline_checksum() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let hello = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    let source = |options: &[&str]| Source {
+        options: options.iter().map(|x| x.to_string()).collect(),
+        ..tbl(format!("http://{addr}/foo.tar.gz"))
+    };
+    let opts = UpdateChecksumOptions::default();
+
+    let res = update_from_sources(&[source(&[&format!("sha256={hello}")])], |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res[0].checksum, format!("sha256::{hello}"));
+
+    let wrong = format!("sha256={}", "0".repeat(64));
+    let e = update_from_sources(&[source(&[&wrong])], |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(format!("{e:?}").contains("Checksum mismatch"));
 
+    assert!(
+        update_from_sources(&[source(&["sha256=abc"])], |_| {}, &opts)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_normalize_only() {
+    let mut spec = r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.gz git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="SHA256::abc   SKIP"
+CHKUPDATE="anitya::id=1"
+"#
+    .to_string();
+
+    let opts = UpdateChecksumOptions {
+        normalize_only: true,
+        ..Default::default()
+    };
+    let res = get_new_spec_with_options(&mut spec, |_| {}, &opts)
+        .await
+        .unwrap();
+
+    assert!(res.changed());
     assert_eq!(
         spec,
-        r#"VER=5.115.0
-SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz"
-CHKSUMS="sha256::xyz"
-CHKUPDATE="anitya::id=8762""#
-            .to_string()
+        r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.gz git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="sha256::abc \
+         SKIP"
+CHKUPDATE="anitya::id=1"
+"#
     );
+}
 
-    let map2 = [(
-        "CHKSUMS".to_string(),
-        vec![
-            "SKIP".to_string(),
-            "sha256::95ecac13c3f8c69e3e80fa73864102f13730b5fd87e7438c23b96766ef458e41".to_string(),
-            "sha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb".to_string(),
-        ],
-    )]
-    .into_iter()
-    .collect::<HashMap<_, _>>();
+#[tokio::test]
+async fn test_cross_check() {
+    assert_eq!(
+        cross_check_url(
+            "https://example.org/foo/foo-1.0.tar.gz?raw=1",
+            "https://mirror.example.org/pub/"
+        )
+        .as_deref(),
+        Some("https://mirror.example.org/pub/foo/foo-1.0.tar.gz?raw=1")
+    );
+    assert_eq!(
+        cross_check_url("foo-1.0.tar.gz", "https://mirror.example.org"),
+        None
+    );
 
-    let mut spec = r#"VER=3.113
-SRCS="git::commit=tags/v$VER::https://github.com/lxgw/kose-font \
-      file::rename=XiaolaiMonoSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiMonoSC-Regular.ttf \
-      file::rename=XiaolaiSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiSC-Regular.ttf"
-CHKSUMS="SKIP \
-         sha256::95ecac13c3f8csha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb73864102f13730b5fd87e7438c23b96766ef458e41 \
-         sha256::x"
-CHKUPDATE="anitya::id=374941""#.to_string();
+    let primary = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let good = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let bad = serve(&[("GET /foo.tar.gz", "hello world!")]).await;
+    let sources = [tbl(format!("http://{primary}/foo.tar.gz"))];
+
+    let opts = UpdateChecksumOptions {
+        cross_check: Some(format!("http://{good}")),
+        ..Default::default()
+    };
+    assert!(update_from_sources(&sources, |_| {}, &opts).await.is_ok());
+
+    let opts = UpdateChecksumOptions {
+        cross_check: Some(format!("http://{bad}")),
+        ..Default::default()
+    };
+    let e = update_from_sources(&sources, |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(format!("{e:?}").contains("possible tampering"));
+}
+
+#[test]
+fn test_list_sources() {
+    let spec = r#"VER=1.0
+SRCS="tbl::https://example.org/foo-$VER.tar.gz \
+      git::commit=tags/v$VER::https://example.org/foo.git \
+      tbl::https://example.org/nightly/foo.tar.gz"
+CHKSUMS="sha256::abc SKIP SKIP"
+"#;
+    let opts = UpdateChecksumOptions {
+        ignore: IgnoreList::parse("https://example.org/nightly/*").unwrap(),
+        ..Default::default()
+    };
+
+    let sources = list_sources(spec, &opts).unwrap();
+
+    assert_eq!(
+        sources
+            .iter()
+            .map(|x| (x.class, x.url.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            (SourceClass::Hash, "https://example.org/foo-1.0.tar.gz"),
+            (SourceClass::Skip, "https://example.org/foo.git"),
+            (
+                SourceClass::Ignored,
+                "https://example.org/nightly/foo.tar.gz"
+            ),
+        ]
+    );
+
+    // left to resolve to the latest release, without looking it up
+    let spec = r#"SRCS="pypi::foo tbl::https://example.org/bar.tar.gz"
+CHKSUMS="SKIP SKIP"
+"#;
+    assert!(list_sources(spec, &UpdateChecksumOptions::default()).is_err());
+    let opts = UpdateChecksumOptions {
+        pypi_latest: true,
+        offline: true,
+        ..Default::default()
+    };
+
+    let sources = list_sources(spec, &opts).unwrap();
+    assert_eq!(
+        sources
+            .iter()
+            .map(|x| (x.class, x.typ.as_str(), x.url.as_str()))
+            .collect::<Vec<_>>(),
+        [
+            (SourceClass::Hash, "pypi", "foo"),
+            (SourceClass::Hash, "tbl", "https://example.org/bar.tar.gz"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_custom_method() {
+    let addr = serve(&[("POST /download", "hello world")]).await;
+    let source = |options: &[&str]| Source {
+        options: options.iter().map(|x| x.to_string()).collect(),
+        ..tbl(format!("http://{addr}/download"))
+    };
+    let opts = UpdateChecksumOptions::default();
+
+    let res = update_from_sources(&[source(&["method=post", "body=id=1"])], |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+
+    assert!(update_from_sources(&[source(&[])], |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_body_not_cached() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let source = |options: &[&str]| Source {
+        options: options.iter().map(|x| x.to_string()).collect(),
+        ..tbl(format!("http://{addr}/foo.tar.gz"))
+    };
+    let opts = UpdateChecksumOptions::default();
+    update_from_sources(&[source(&[])], |_| {}, &opts)
+        .await
+        .unwrap();
+
+    // downloaded again rather than reusing the checksum of the same URL without body
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    let downloaded = AtomicBool::new(false);
+    update_from_sources(
+        &[source(&["body=id=1"])],
+        |event| {
+            if let Event::Downloading { .. } = event {
+                downloaded.store(true, Ordering::Relaxed);
+            }
+        },
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(downloaded.into_inner());
+}
+
+#[tokio::test]
+async fn test_custom_client() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let spec = format!("SRCS=\"tbl::http://{addr}/foo.tar.gz\"\nCHKSUMS=\"SKIP\"\n");
+    // only the default client goes through the proxy, which is not listening
+    let opts = UpdateChecksumOptions {
+        proxy: Some("http://127.0.0.1:1".to_string()),
+        ..Default::default()
+    };
+    assert!(update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .is_err());
+
+    let client = Client::builder().no_proxy().build().unwrap();
+    let mut spec = spec;
+    get_new_spec_with_client(&client, &mut spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert!(spec.contains(HELLO_SHA256));
+}
+
+#[tokio::test]
+async fn test_mirrors() {
+    let addr = serve(&[("GET /good", "hello world")]).await;
+    let source = |mirrors: &[&str]| Source {
+        options: mirrors
+            .iter()
+            .map(|x| format!("mirror=http://{addr}{x}"))
+            .collect(),
+        ..tbl(format!("http://{addr}/missing"))
+    };
+    let opts = UpdateChecksumOptions::default();
+
+    let res = update_from_sources(&[source(&["/gone", "/good"])], |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res[0].url, format!("http://{addr}/missing"));
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+
+    let e = update_from_sources(&[source(&["/gone"])], |_| {}, &opts)
+        .await
+        .unwrap_err();
+    let e = format!("{e:?}");
+    assert!(e.contains(&format!("http://{addr}/missing: ")));
+    assert!(e.contains(&format!("http://{addr}/gone: ")));
+}
+
+/// Specs exercising the rewriting paths, with `@HOST@` standing for the mock server.
+#[cfg(test)]
+const IDEMPOTENCY_CORPUS: &[&str] = &[
+    r#"VER=1.0
+SRCS="tbl::http://@HOST@/foo-$VER.tar.gz"
+CHKSUMS="sha256::0000000000000000000000000000000000000000000000000000000000000000"
+CHKUPDATE="anitya::id=1"
+"#,
+    r#"VER=1.0
+SRCS="tbl::http://@HOST@/foo-$VER.tar.gz \
+      git::commit=tags/v$VER::https://example.org/bar.git \
+      tbl::rename=baz.tar.gz::http://@HOST@/baz.tar.gz"
+"#,
+    r#"VER=1.0
+SRCS__AMD64="tbl::http://@HOST@/foo-$VER.tar.gz"
+SRCS__ARM64="tbl::http://@HOST@/baz.tar.gz"
+CHKSUMS__AMD64="SKIP"
+CHKUPDATE="anitya::id=1"
+"#,
+    r#"VER=1.0
+SRCS="tbl::algo=sha512::http://@HOST@/foo-$VER.tar.gz \
+      tbl::http://@HOST@/part1|http://@HOST@/part2"
+CHKSUMS="SKIP SKIP"
+"#,
+];
+
+#[tokio::test]
+async fn test_idempotency() {
+    let addr = serve(&[
+        ("GET /foo-1.0.tar.gz", "hello world"),
+        ("GET /baz.tar.gz", "hello baz"),
+        ("GET /part1", "hello "),
+        ("GET /part2", "world"),
+    ])
+    .await;
+    let opts = UpdateChecksumOptions::default();
+
+    for fixture in IDEMPOTENCY_CORPUS {
+        let mut spec = fixture.replace("@HOST@", &addr.to_string());
+        get_new_spec_with_options(&mut spec, |_| {}, &opts)
+            .await
+            .unwrap();
+        let first = spec.clone();
+
+        let res = get_new_spec_with_options(&mut spec, |_| {}, &opts)
+            .await
+            .unwrap();
+        assert!(!res.changed(), "second run changed {first}");
+        assert_eq!(spec, first);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_proxy() {
+    let path =
+        std::env::temp_dir().join(format!("abbs-update-checksum-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            respond(stream, &[("GET /foo.tar.gz", "hello world")]).await;
+        }
+    });
+
+    // the host is never resolved, everything goes over the socket
+    let sources = [tbl("http://example.invalid/foo.tar.gz")];
+    let opts = UpdateChecksumOptions {
+        proxy: Some(format!("unix://{}", path.display())),
+        ..Default::default()
+    };
+
+    let res = update_from_sources(&sources, |_| {}, &opts).await;
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(res.unwrap()[0].checksum, HELLO_SHA256);
+}
+
+#[tokio::test]
+async fn test_new_source_without_checksum() {
+    let addr = serve(&[
+        ("GET /foo.tar.gz", "hello world"),
+        ("GET /baz.tar.gz", "hello baz"),
+        ("GET /qux.tar.gz", "hello qux"),
+    ])
+    .await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz tbl::http://{addr}/baz.tar.gz tbl::http://{addr}/qux.tar.gz"
+CHKSUMS="sha512::0 sha256::0"
+"#
+    );
+
+    let res = update_from_str_with_options(&spec, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        res.checksums["CHKSUMS"],
+        [
+            "sha512::309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f",
+            "sha256::48cd07994032fc4ceeb8f0ada4916544de25f3c5972c428ea6f294af0a116cd6",
+            "sha256::b19547fc08ab7a741ba97a4ccf339a9ffa0c2fff134d29f721d8ff850b13b738",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_custom_hasher() {
+    struct Length(usize);
+
+    impl ChecksumHasher for Length {
+        fn update(&mut self, data: &[u8]) {
+            self.0 += data.len();
+        }
+
+        fn finalize_hex(self: Box<Self>) -> String {
+            format!("{:x}", self.0)
+        }
+
+        fn prefix(&self) -> &str {
+            "len"
+        }
+    }
+
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="len::5"
+"#
+    );
+    let opts = UpdateChecksumOptions {
+        hasher: Some(CustomHasher::new(|| Box::new(Length(0)))),
+        ..Default::default()
+    };
+
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], ["len::b"]);
+    assert!(res.warnings.is_empty());
+}
+
+#[test]
+fn test_parse_context() {
+    let context = parse_context("VER=1.0\nCHKUPDATE=\"anitya::id=1\"\n", false).unwrap();
+    assert_eq!(context["VER"], "1.0");
+    assert_eq!(context["CHKUPDATE"], "anitya::id=1");
 
-    update_spec_inner(map2, &mut spec);
+    let broken = "VER=1.0\nSRCS=\"tbl::https://example.org/foo-$VER.tar.gz\n";
+    let e = parse_context(broken, false).unwrap_err();
+    assert!(matches!(e.class(), UpdateChecksumError::Parse(_)));
+    // left to expand by the fallback parser
+    let context = parse_context(broken, true).unwrap();
+    assert_eq!(context["VER"], "1.0");
+    assert_eq!(context["SRCS"], "tbl::https://example.org/foo-1.0.tar.gz");
+}
+
+#[tokio::test]
+async fn test_write_sizes() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let mut spec = format!(
+        r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git \
+      tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="SKIP sha256::abc"
+CHKUPDATE="anitya::id=1"
+"#
+    );
+    let opts = UpdateChecksumOptions {
+        write_sizes: true,
+        ..Default::default()
+    };
+
+    let res = get_new_spec_with_options(&mut spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.sizes["CHKSIZE"], ["SKIP", "11"]);
+    assert!(spec.ends_with(
+        "CHKSUMS=\"SKIP \\\n         \
+         sha256::b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9\"\n\
+         CHKSIZE=\"SKIP \\\n         11\"\n\
+         CHKUPDATE=\"anitya::id=1\"\n"
+    ));
+}
+
+#[tokio::test]
+async fn test_no_create() {
+    let mut spec = r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git"
+CHKSUMS="SKIP"
+SRCS__riscv64="git::commit=tags/v1.0::https://example.org/foo-riscv64.git"
+"#
+    .to_string();
+    let orig = spec.clone();
+
+    let opts = UpdateChecksumOptions {
+        no_create: true,
+        ..Default::default()
+    };
+    let res = get_new_spec_with_options(&mut spec, |_| {}, &opts)
+        .await
+        .unwrap();
+
+    assert!(!res.checksums.contains_key("CHKSUMS__riscv64"));
+    assert_eq!(spec, orig);
+}
+
+#[cfg(feature = "openpgp")]
+#[tokio::test]
+async fn test_signed_source() {
+    let addr = serve(&[
+        ("GET /foo.tar.gz", "hello world"),
+        ("GET /bad.tar.gz", "hello world!"),
+        (
+            "GET /foo.tar.gz.asc",
+            include_str!("../testdata/hello-world.asc"),
+        ),
+    ])
+    .await;
+    let source = |name: &str| Source {
+        options: vec![format!("sig=http://{addr}/foo.tar.gz.asc")],
+        ..tbl(format!("http://{addr}/{name}"))
+    };
+    let opts = UpdateChecksumOptions {
+        keyring: Some(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/signer.asc")),
+        ..Default::default()
+    };
+
+    let res = update_from_sources(&[source("foo.tar.gz")], |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+
+    assert!(update_from_sources(&[source("bad.tar.gz")], |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "openpgp")]
+#[tokio::test]
+async fn test_verify_signatures() {
+    let addr = serve(&[
+        ("GET /foo.tar.gz", "hello world"),
+        (
+            "GET /foo.tar.gz.asc",
+            include_str!("../testdata/hello-world.asc"),
+        ),
+        ("GET /unsigned.tar.gz", "hello world"),
+    ])
+    .await;
+    let source = |name: &str| tbl(format!("http://{addr}/{name}"));
+    let opts = UpdateChecksumOptions {
+        keyring: Some(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/signer.asc")),
+        verify_signatures: true,
+        ..Default::default()
+    };
+
+    // found at `.asc` after `.sig` is missing
+    let res = update_from_sources(&[source("foo.tar.gz")], |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+
+    let e = update_from_sources(&[source("unsigned.tar.gz")], |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(format!("{e:?}").contains("unsigned.tar.gz.asc"));
+}
+
+#[test]
+fn test_escaped_quote() {
+    let mut spec = r#"VER=1.0
+DESC="A \"quoted\" description, wrapped
+CHKSUMS=\"not an assignment\""
+SRCS="tbl::https://example.org/foo-$VER.tar.gz?name=\"foo\" \
+      git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="SKIP SKIP"
+"#
+    .to_string();
+
+    let context = parse_spec(&spec, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        split_srcs(&context["SRCS"])[0],
+        r#"tbl::https://example.org/foo-1.0.tar.gz?name="foo""#
+    );
+
+    // an escaped `$` is not expanded again
+    let escaped = r#"VER=1.0
+FOO=bar
+SRCS="tbl::https://example.org/foo-$VER.tar.gz?sig=\$FOO"
+CHKSUMS="SKIP"
+"#;
+    let sources = list_sources(escaped, &UpdateChecksumOptions::default()).unwrap();
+    assert_eq!(
+        sources[0].url,
+        "https://example.org/foo-1.0.tar.gz?sig=$FOO"
+    );
 
+    update_spec_inner(
+        HashMap::from([(
+            "CHKSUMS".to_string(),
+            vec!["sha256::abc".to_string(), "SKIP".to_string()],
+        )]),
+        &mut spec,
+        &UpdateChecksumOptions::default(),
+    )
+    .unwrap();
     assert_eq!(
         spec,
-r#"VER=3.113
-SRCS="git::commit=tags/v$VER::https://github.com/lxgw/kose-font \
-      file::rename=XiaolaiMonoSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiMonoSC-Regular.ttf \
-      file::rename=XiaolaiSC-Regular.ttf::https://github.com/lxgw/kose-font/releases/download/v$VER/XiaolaiSC-Regular.ttf"
-CHKSUMS="SKIP \
-         sha256::95ecac13c3f8c69e3e80fa73864102f13730b5fd87e7438c23b96766ef458e41 \
-         sha256::b04eec580794279f6178644f6d7af090bd9bcbd3fb3b6873f3c714e21fa514fb"
-CHKUPDATE="anitya::id=374941""#.to_string()
+        r#"VER=1.0
+DESC="A \"quoted\" description, wrapped
+CHKSUMS=\"not an assignment\""
+SRCS="tbl::https://example.org/foo-$VER.tar.gz?name=\"foo\" \
+      git::commit=tags/v$VER::https://example.org/bar.git"
+CHKSUMS="sha256::abc \
+         SKIP"
+"#
+    );
+}
+
+#[tokio::test]
+async fn test_repair() {
+    // port 1 is never listened on, anything downloaded fails
+    let spec = r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git \
+      tbl::http://127.0.0.1:1/foo.tar.gz"
+CHKSUMS="sha256::abc SKIP"
+SRCS__amd64="tbl::http://127.0.0.1:1/foo.tar.gz git::commit=tags/v1.0::https://example.org/foo.git"
+CHKSUMS__amd64="sha256::abc SKIP"
+"#;
+    let opts = UpdateChecksumOptions {
+        repair: true,
+        ..Default::default()
+    };
+
+    let res = update_from_str_with_options(spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], ["SKIP", "sha256::abc"]);
+    assert_eq!(res.checksums["CHKSUMS__amd64"], ["sha256::abc", "SKIP"]);
+    assert_eq!(res.repaired, ["CHKSUMS"]);
+
+    // two tarballs could be in either order
+    let spec = r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git \
+      tbl::http://127.0.0.1:1/foo.tar.gz tbl::http://127.0.0.1:1/bar.tar.gz"
+CHKSUMS="sha256::abc sha256::def SKIP"
+"#;
+    assert!(update_from_str_with_options(spec, |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_mismatched() {
+    let addr = serve(&[
+        ("GET /foo.tar.gz", "hello world"),
+        ("GET /bar.tar.gz", "hello world"),
+    ])
+    .await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz tbl::http://{addr}/bar.tar.gz"
+CHKSUMS="{HELLO_SHA256}"
+SRCS__amd64="tbl::http://{addr}/foo.tar.gz"
+CHKSUMS__amd64="{HELLO_SHA256}"
+"#
+    );
+
+    let res = update_from_str_with_options(&spec, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"].len(), 2);
+    assert_eq!(res.mismatched, ["CHKSUMS"]);
+    assert_eq!(res.warnings.len(), 1);
+
+    let opts = UpdateChecksumOptions {
+        strict: true,
+        ..Default::default()
+    };
+    assert!(update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_unsupported_algo() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="md5::5eb63bbbe01eeed093cb22bb8f5acdc3"
+"#
+    );
+
+    let res = update_from_str_with_options(&spec, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+    assert_eq!(res.warnings.len(), 1);
+
+    let opts = UpdateChecksumOptions {
+        strict: true,
+        ..Default::default()
+    };
+    assert!(update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_algorithm() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let spec = format!(
+        r#"SRCS="tbl::http://{addr}/foo.tar.gz"
+CHKSUMS="{HELLO_SHA256}"
+"#
     );
+    let opts = UpdateChecksumOptions {
+        algorithm: Some(ChecksumAlgo::Sha512),
+        ..Default::default()
+    };
+
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(
+        res.checksums["CHKSUMS"],
+        ["sha512::309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f"]
+    );
+}
+
+#[tokio::test]
+async fn test_retries() {
+    // fails once, then succeeds
+    let (addr, _) = serve_raw(&[
+        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+    ])
+    .await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+
+    // not found is not retried
+    let opts = UpdateChecksumOptions {
+        retries: 1,
+        ..Default::default()
+    };
+    let res = update_from_sources(&sources, |_| {}, &opts).await;
+    assert!(res.is_err());
+
+    let (addr, _) = serve_raw(&[
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+    ])
+    .await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+
+    let res = update_from_sources(&sources, |_| {}, &opts).await.unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+}
+
+#[tokio::test]
+async fn test_resume() {
+    // drops the connection halfway through, then serves the rest
+    let (addr, requests) = serve_raw(&[
+        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nAccept-Ranges: bytes\r\n\r\nhello ",
+        b"HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\n\r\nworld",
+    ])
+    .await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+    let opts = UpdateChecksumOptions {
+        retries: 1,
+        resume: true,
+        ..Default::default()
+    };
+
+    let res = update_from_sources(&sources, |_| {}, &opts).await.unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+    assert_eq!(res[0].size, Some(11));
+    // only the rest is requested again
+    let requests = requests.lock().unwrap();
+    assert!(requests[1].to_lowercase().contains("range: bytes=6-"));
+}
+
+#[tokio::test]
+async fn test_truncated() {
+    let (addr, _) = serve_raw(&[b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello "]).await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+
+    let e = update_from_sources(&sources, |_| {}, &UpdateChecksumOptions::default())
+        .await
+        .unwrap_err();
+    assert!(format!("{e:?}").contains(&format!("http://{addr}/foo.tar.gz")));
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    // accepts connections but never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+    let opts = UpdateChecksumOptions {
+        timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+
+    let e = update_from_sources(&sources, |_| {}, &opts)
+        .await
+        .unwrap_err();
+    let msg = format!("Timed out downloading http://{addr}/foo.tar.gz");
+    assert!(format!("{e:?}").contains(&msg));
+    drop(listener);
+}
+
+#[tokio::test]
+async fn test_proxy() {
+    // proxies are sent the absolute URL
+    let proxy = serve(&[("GET http://example.invalid/foo.tar.gz", "hello world")]).await;
+    let proxy = format!("http://{proxy}");
+    let sources = [tbl("http://example.invalid/foo.tar.gz")];
+    let opts = UpdateChecksumOptions {
+        proxy: Some(proxy.clone()),
+        ..Default::default()
+    };
+
+    let res = update_from_sources(&sources, |_| {}, &opts).await.unwrap();
+    assert_eq!(res[0].checksum, HELLO_SHA256);
+
+    let get = |env: &'static [(&'static str, &'static str)]| {
+        let proxy = proxy.clone();
+        async move {
+            let var = |name: &str| match name {
+                "all_proxy" => Some(proxy.clone()),
+                _ => env
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string()),
+            };
+            let mut builder = ClientBuilder::new();
+            for proxy in env_proxies(var).unwrap() {
+                builder = builder.proxy(proxy);
+            }
+
+            builder
+                .build()
+                .unwrap()
+                .get("http://example.invalid/foo.tar.gz")
+                .send()
+                .await
+        }
+    };
+
+    assert!(get(&[]).await.unwrap().status().is_success());
+    // example.invalid never resolves without the proxy
+    assert!(get(&[("NO_PROXY", "example.invalid")]).await.is_err());
+}
+
+#[tokio::test]
+async fn test_html_page() {
+    let addr = serve(&[
+        (
+            "GET /foo.tar.gz",
+            "\n<!DOCTYPE html><title>Not Found</title>",
+        ),
+        ("GET /index.html", "<html></html>"),
+    ])
+    .await;
+    let opts = UpdateChecksumOptions::default();
+
+    let e = update_from_str_with_options(
+        &format!("SRCS=\"tbl::http://{addr}/foo.tar.gz\"\n"),
+        |_| {},
+        &opts,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::HtmlPage { url } if *url == format!("http://{addr}/foo.tar.gz")
+    ));
+
+    // not expected to be an archive
+    update_from_str_with_options(
+        &format!("SRCS=\"file::http://{addr}/index.html\"\n"),
+        |_| {},
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    let opts = UpdateChecksumOptions {
+        check_html: false,
+        ..Default::default()
+    };
+    update_from_str_with_options(
+        &format!("SRCS=\"tbl::http://{addr}/foo.tar.gz\"\n"),
+        |_| {},
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert!(is_html_type("text/html; charset=utf-8"));
+    assert!(!is_html_type("application/gzip"));
+}
+
+#[tokio::test]
+async fn test_unknown_length() {
+    // the body ends with the connection
+    let (addr, _) = serve_raw(&[b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello world"]).await;
+    let sources = [tbl(format!("http://{addr}/foo.tar.gz"))];
+    let dir = tempfile::tempdir().unwrap();
+    let opts = UpdateChecksumOptions {
+        store: Some(SourceStore::new(dir.path())),
+        ..Default::default()
+    };
+
+    let totals = std::sync::Mutex::new(vec![]);
+    let totals_ref = &totals;
+    let res = update_from_sources(
+        &sources,
+        |event| {
+            if let Event::Downloading { total, .. } = event {
+                totals_ref.lock().unwrap().push(total);
+            }
+        },
+        &opts,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(res[0].size, Some(11));
+    let totals = totals.into_inner().unwrap();
+    assert!(!totals.is_empty() && totals.iter().all(|x| *x == 0));
+    // could have been truncated, so it is not stored
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_offline() {
+    let addr = serve(&[("GET /foo.tar.gz", "hello world")]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let spec = format!("SRCS=\"tbl::http://{addr}/foo.tar.gz\"\n");
+
+    let opts = UpdateChecksumOptions {
+        store: Some(SourceStore::new(dir.path())),
+        ..Default::default()
+    };
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+
+    // a new run, not reusing the checksums of the previous one
+    let opts = UpdateChecksumOptions {
+        store: Some(SourceStore::new(dir.path())),
+        offline: true,
+        ..Default::default()
+    };
+    let res = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.checksums["CHKSUMS"], [HELLO_SHA256]);
+
+    let e = update_from_str_with_options(
+        &format!("SRCS=\"tbl::http://{addr}/bar.tar.gz\"\n"),
+        |_| {},
+        &opts,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::NotStored { url } if *url == format!("http://{addr}/bar.tar.gz")
+    ));
+
+    // a truncated stored body counts as missing
+    let body = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .find(|x| x.extension().is_none())
+        .unwrap();
+    std::fs::write(&body, "hello ").unwrap();
+    let opts = UpdateChecksumOptions {
+        cache: ResponseCache::default(),
+        ..opts
+    };
+    let e = update_from_str_with_options(&spec, |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::NotStored { url } if *url == format!("http://{addr}/foo.tar.gz")
+    ));
+
+    let opts = UpdateChecksumOptions {
+        pypi_latest: true,
+        ..opts
+    };
+    let e = update_from_str_with_options("SRCS=\"pypi::foo\"\n", |_| {}, &opts)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.class(),
+        UpdateChecksumError::NotStored { url } if url == "https://pypi.org/pypi/foo/json"
+    ));
 }
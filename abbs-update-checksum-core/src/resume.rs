@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use eyre::Result;
+use eyre::WrapErr;
+
+/// Bytes of a source downloaded so far, spooled to a temporary file so that a
+/// retry can continue where the previous attempt stopped.
+pub(crate) struct Partial {
+    file: File,
+    len: u64,
+    /// Index of the part of `a.part1|a.part2` being downloaded.
+    pub(crate) part: usize,
+    /// Offset in the file at which that part starts.
+    part_start: u64,
+    /// Sum of the `Content-Length` of the parts before it.
+    pub(crate) done_size: u64,
+    /// Whether the server of that part advertised `Accept-Ranges: bytes`.
+    pub(crate) ranges: bool,
+}
+
+impl Partial {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            file: tempfile::tempfile().wrap_err("Failed to create a temporary file")?,
+            len: 0,
+            part: 0,
+            part_start: 0,
+            done_size: 0,
+            ranges: false,
+        })
+    }
+
+    /// Offset in the current part to resume from, 0 if it has to start over.
+    pub(crate) fn offset(&self) -> u64 {
+        if self.ranges {
+            self.len - self.part_start
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn save(&mut self, chunk: &[u8]) -> Result<()> {
+        self.file.write_all(chunk)?;
+        self.len += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Drops what was saved of the current part.
+    pub(crate) fn restart_part(&mut self) -> Result<()> {
+        self.file.set_len(self.part_start)?;
+        self.file.seek(SeekFrom::Start(self.part_start))?;
+        self.len = self.part_start;
+
+        Ok(())
+    }
+
+    /// Moves on to the next part, after `done_size` bytes were announced so far.
+    pub(crate) fn next_part(&mut self, done_size: u64) {
+        self.part += 1;
+        self.part_start = self.len;
+        self.done_size = done_size;
+        self.ranges = false;
+    }
+
+    /// Feeds everything saved so far to `f`, in order.
+    pub(crate) fn replay(&mut self, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+
+        self.file.rewind()?;
+        loop {
+            let n = self.file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            f(&buf[..n])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_partial() {
+    let mut partial = Partial::new().unwrap();
+    partial.save(b"hello ").unwrap();
+    partial.next_part(6);
+    partial.save(b"wor").unwrap();
+    assert_eq!(partial.offset(), 0);
+
+    partial.ranges = true;
+    assert_eq!(partial.offset(), 3);
+
+    let mut replayed = vec![];
+    partial
+        .replay(|x| {
+            replayed.extend_from_slice(x);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(replayed, b"hello wor");
+
+    partial.restart_part().unwrap();
+    partial.save(b"world").unwrap();
+    let mut replayed = vec![];
+    partial
+        .replay(|x| {
+            replayed.extend_from_slice(x);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(replayed, b"hello world");
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+
+use eyre::bail;
+use eyre::Result;
+use eyre::WrapErr;
+use pgp::types::PublicKeyTrait;
+use pgp::Deserializable;
+use pgp::SignedPublicKey;
+use pgp::StandaloneSignature;
+
+/// Download of a source with a detached signature, spooled to a temporary file
+/// so that it can be verified once complete.
+pub(crate) struct Spool {
+    file: File,
+}
+
+impl Spool {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            file: tempfile::tempfile().wrap_err("Failed to create a temporary file")?,
+        })
+    }
+
+    pub(crate) fn update(&mut self, chunk: &[u8]) -> Result<()> {
+        Ok(self.file.write_all(chunk)?)
+    }
+
+    /// Checks that `signature`, armored or binary, was made over the spooled data
+    /// by one of the keys of `keyring`.
+    pub(crate) fn verify(mut self, signature: &[u8], keyring: &Path) -> Result<()> {
+        let signature = if is_armored(signature) {
+            StandaloneSignature::from_armor_single(signature)?.0
+        } else {
+            StandaloneSignature::from_bytes(signature)?
+        };
+
+        for key in load_keyring(keyring)? {
+            if self.verify_with(&signature, &key)? {
+                return Ok(());
+            }
+
+            for subkey in &key.public_subkeys {
+                if self.verify_with(&signature, subkey)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!(
+            "Bad signature, or not made by any key of {}",
+            keyring.display()
+        )
+    }
+
+    fn verify_with(
+        &mut self,
+        signature: &StandaloneSignature,
+        key: &impl PublicKeyTrait,
+    ) -> Result<bool> {
+        self.file.rewind()?;
+
+        Ok(signature.signature.verify(key, &mut self.file).is_ok())
+    }
+}
+
+fn is_armored(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN PGP")
+}
+
+/// Loads the public keys of an armored or binary keyring.
+fn load_keyring(path: &Path) -> Result<Vec<SignedPublicKey>> {
+    let data = std::fs::read(path)
+        .wrap_err_with(|| format!("Failed to read keyring {}", path.display()))?;
+
+    let keys = if is_armored(&data) {
+        SignedPublicKey::from_armor_many(&*data)?
+            .0
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        SignedPublicKey::from_bytes_many(&*data).collect::<Result<Vec<_>, _>>()?
+    };
+
+    if keys.is_empty() {
+        bail!("Keyring {} contains no public key", path.display());
+    }
+
+    Ok(keys)
+}
+
+#[test]
+fn test_verify() {
+    let keyring = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/signer.asc");
+    let signature = include_bytes!("../testdata/hello-world.asc");
+
+    let mut spool = Spool::new().unwrap();
+    spool.update(b"hello ").unwrap();
+    spool.update(b"world").unwrap();
+    spool.verify(signature, &keyring).unwrap();
+
+    let mut spool = Spool::new().unwrap();
+    spool.update(b"hello world!").unwrap();
+    assert!(spool.verify(signature, &keyring).is_err());
+}
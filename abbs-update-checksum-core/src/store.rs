@@ -0,0 +1,141 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use eyre::bail;
+use eyre::Result;
+use eyre::WrapErr;
+use faster_hex::hex_string;
+use log::debug;
+use sha2::Digest;
+use sha2::Sha256;
+use tempfile::NamedTempFile;
+
+/// Directory the bodies of downloaded sources are kept in across runs, each in a
+/// file named after the SHA-256 of its URL, with its size in the same name ending
+/// with `.size`, read instead of downloading the source again.
+#[derive(Debug, Clone)]
+pub struct SourceStore {
+    dir: PathBuf,
+}
+
+impl SourceStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(hex_string(&Sha256::digest(url.as_bytes())))
+    }
+
+    fn size_path(path: &Path) -> PathBuf {
+        path.with_extension("size")
+    }
+
+    /// Returns the file holding the body of `url`, if it was stored with the size
+    /// it has.
+    pub(crate) fn get(&self, url: &str) -> Option<PathBuf> {
+        let path = self.path(url);
+        let len = fs::metadata(&path).ok().filter(|x| x.is_file())?.len();
+        let size = fs::read_to_string(Self::size_path(&path))
+            .ok()
+            .and_then(|x| x.trim().parse::<u64>().ok());
+
+        if size != Some(len) {
+            debug!("Stored body of {url} is {len} bytes, expected {size:?}, ignoring it");
+            return None;
+        }
+
+        Some(path)
+    }
+
+    /// Starts storing the body of `url`. It only replaces the stored one once
+    /// persisted, after the whole body was received and its size checked, so that
+    /// no run reads a truncated one.
+    pub(crate) fn entry(&self, url: &str) -> Result<Entry> {
+        fs::create_dir_all(&self.dir)
+            .wrap_err_with(|| format!("Failed to create {}", self.dir.display()))?;
+        let file = NamedTempFile::new_in(&self.dir).wrap_err_with(|| {
+            format!(
+                "Failed to create a temporary file in {}",
+                self.dir.display()
+            )
+        })?;
+
+        Ok(Entry {
+            file,
+            path: self.path(url),
+            size: 0,
+        })
+    }
+}
+
+/// Body of a source being stored, see [`SourceStore::entry`].
+pub(crate) struct Entry {
+    file: NamedTempFile,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Entry {
+    pub(crate) fn save(&mut self, chunk: &[u8]) -> Result<()> {
+        self.file.write_all(chunk)?;
+        self.size += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Replaces the stored body with this one, which must be of the `expected`
+    /// size, e.g. announced with `Content-Length`.
+    pub(crate) fn persist(self, expected: u64) -> Result<()> {
+        if self.size != expected {
+            bail!(
+                "Refusing to store {}: expected {expected} bytes, got {}",
+                self.path.display(),
+                self.size
+            );
+        }
+
+        // a body without its size is ignored by `get`
+        let size_path = SourceStore::size_path(&self.path);
+        fs::write(&size_path, expected.to_string())
+            .wrap_err_with(|| format!("Failed to write {}", size_path.display()))?;
+        self.file
+            .persist(&self.path)
+            .wrap_err_with(|| format!("Failed to write {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_source_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SourceStore::new(dir.path().join("sources"));
+    let url = "https://example.org/foo.tar.gz";
+
+    let mut entry = store.entry(url).unwrap();
+    entry.save(b"hello ").unwrap();
+    entry.save(b"world").unwrap();
+    // not stored until persisted
+    assert_eq!(store.get(url), None);
+    entry.persist(11).unwrap();
+
+    let path = store.get(url).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"hello world");
+    assert_eq!(store.get("https://example.org/bar.tar.gz"), None);
+    // an abandoned entry leaves nothing behind
+    drop(store.entry(url).unwrap());
+    assert_eq!(fs::read_dir(dir.path().join("sources")).unwrap().count(), 2);
+
+    // nor does one of another size than expected
+    let mut entry = store.entry(url).unwrap();
+    entry.save(b"hello").unwrap();
+    assert!(entry.persist(11).is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"hello world");
+
+    // a truncated stored file is not used
+    fs::write(&path, b"hello ").unwrap();
+    assert_eq!(store.get(url), None);
+}
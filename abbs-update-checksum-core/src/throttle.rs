@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Limit of the bytes per second received by every download sharing it.
+///
+/// Clones share the same limit.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// When the bytes let through so far are paid for.
+    next: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next: Arc::default(),
+        }
+    }
+
+    /// Waits until `n` more bytes fit in the limit.
+    pub(crate) async fn acquire(&self, n: usize) {
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            // idle time is not saved up for a later burst
+            let start = next.map_or_else(Instant::now, |x| x.max(Instant::now()));
+            let until = start + Duration::from_secs_f64(n as f64 / self.bytes_per_sec as f64);
+            *next = Some(until);
+            until
+        };
+
+        tokio::time::sleep_until(until).await;
+    }
+}
+
+// in virtual time, which only advances when every task waits, to the next timer
+#[tokio::test(start_paused = true)]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::new(1000);
+    let start = Instant::now();
+
+    let other = limiter.clone();
+    tokio::join!(limiter.acquire(100), other.acquire(100));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(210));
+
+    // idle time is not saved up
+    tokio::time::advance(Duration::from_millis(300)).await;
+    let start = Instant::now();
+    limiter.acquire(100).await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_millis(110));
+}
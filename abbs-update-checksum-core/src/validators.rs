@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use eyre::Result;
+use eyre::WrapErr;
+use log::debug;
+use reqwest::header::HeaderMap;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::header::ETAG;
+use reqwest::header::LAST_MODIFIED;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::checksum::parse_checksum;
+use crate::ChecksumAlgo;
+use crate::Hashed;
+
+/// `ETag` and `Content-Length` of the responses sources were last hashed from,
+/// kept across runs to tell with a `HEAD` request whether a source changed.
+///
+/// Clones share the same entries.
+#[derive(Debug, Clone, Default)]
+pub struct SourceValidators {
+    entries: Arc<Mutex<HashMap<String, Validators>>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Validators {
+    checksum: String,
+    /// Bytes hashed, `None` in files saved before it was recorded.
+    size: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    length: Option<u64>,
+}
+
+impl Validators {
+    /// Returns `None` for a response with neither `ETag` nor `Last-Modified`,
+    /// which could not be told apart from a changed one: the `Content-Length`
+    /// alone is also the one of a replacement of the same size.
+    fn from_headers(checksum: &str, size: Option<u64>, headers: &HeaderMap) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let length = header(CONTENT_LENGTH).and_then(|x| x.parse().ok());
+
+        (etag.is_some() || last_modified.is_some()).then(|| Self {
+            checksum: checksum.to_string(),
+            size,
+            etag,
+            last_modified,
+            length,
+        })
+    }
+
+    /// Returns whether the validators of both responses are the same.
+    fn matches(&self, other: &Self) -> bool {
+        self.etag == other.etag
+            && self.last_modified == other.last_modified
+            && self.length == other.length
+    }
+}
+
+impl SourceValidators {
+    /// Loads the JSON file saved by [`Self::save`], empty if it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).wrap_err_with(|| format!("Failed to read {}", path.display())),
+        };
+        let entries = serde_json::from_slice(&data)
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Self {
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&*self.entries.lock().unwrap())?;
+
+        std::fs::write(path, data).wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Returns the checksum of `url` if it was last hashed with `algo` to `old`,
+    /// and `headers` of a `HEAD` response match those of that download.
+    pub(crate) fn unchanged(
+        &self,
+        url: &str,
+        algo: ChecksumAlgo,
+        old: &str,
+        headers: &HeaderMap,
+    ) -> Option<Hashed> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(url)?;
+
+        // compared parsed, the case of the algorithm and digest may differ
+        let recorded = parse_checksum(&entry.checksum).filter(|(x, _)| *x == algo);
+        if recorded.is_none() || recorded != parse_checksum(old) {
+            debug!("Checksum of {url} changed since it was last hashed");
+            return None;
+        }
+
+        let Some(size) = entry.size else {
+            debug!("Size of {url} was not recorded");
+            return None;
+        };
+        let current = Validators::from_headers(&entry.checksum, None, headers)?;
+        if !current.matches(entry) {
+            debug!("{url} changed since it was last hashed");
+            return None;
+        }
+
+        debug!("Skipping unchanged {url}");
+
+        Some(Hashed {
+            checksum: entry.checksum.clone(),
+            size,
+            uncompressed_size: None,
+        })
+    }
+
+    /// Records the `headers` of the response `hashed` was computed from.
+    pub(crate) fn insert(&self, url: &str, headers: &HeaderMap, hashed: &Hashed) {
+        let Some(validators) =
+            Validators::from_headers(&hashed.checksum, Some(hashed.size), headers)
+        else {
+            return;
+        };
+        // e.g. of a resumed download, answered with the length of the rest only
+        if validators.length.is_some_and(|x| x != hashed.size) {
+            return;
+        }
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), validators);
+    }
+}
+
+#[test]
+fn test_unchanged() {
+    let headers = |etag: Option<&str>, length: &str| {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag {
+            headers.insert(ETAG, etag.parse().unwrap());
+        }
+        headers.insert(CONTENT_LENGTH, length.parse().unwrap());
+        headers
+    };
+    let modified = |date: &str| {
+        let mut headers = headers(None, "11");
+        headers.insert(LAST_MODIFIED, date.parse().unwrap());
+        headers
+    };
+    let checksum = crate::HELLO_SHA256;
+    let hashed = Hashed {
+        checksum: checksum.to_string(),
+        size: 11,
+        uncompressed_size: None,
+    };
+    let validators = SourceValidators::default();
+    validators.insert("a", &headers(Some("\"1\""), "11"), &hashed);
+    validators.insert("b", &headers(None, "11"), &hashed);
+    validators.insert("c", &headers(Some("\"1\""), "5"), &hashed);
+    validators.insert("d", &modified("Mon, 01 Jan 2024 00:00:00 GMT"), &hashed);
+
+    let sha256 = ChecksumAlgo::Sha256;
+    let unchanged =
+        |url, algo, old: &str, headers| validators.unchanged(url, algo, old, &headers).is_some();
+    let etag = |etag| headers(Some(etag), "11");
+
+    assert!(unchanged("a", sha256, checksum, etag("\"1\"")));
+    let uppercase = checksum.replace("sha256", "SHA256");
+    assert!(unchanged("a", sha256, &uppercase, etag("\"1\"")));
+    assert!(!unchanged("a", sha256, "sha256::abc", etag("\"1\"")));
+    assert!(!unchanged(
+        "a",
+        ChecksumAlgo::Sha512,
+        checksum,
+        etag("\"1\"")
+    ));
+    assert!(!unchanged("a", sha256, checksum, etag("\"2\"")));
+    assert!(!unchanged("a", sha256, checksum, headers(None, "11")));
+    // not recorded, a replacement of the same size would be taken for it
+    assert!(!unchanged("b", sha256, checksum, headers(None, "11")));
+    // not recorded, the length is not the one of the whole source
+    assert!(!unchanged(
+        "c",
+        sha256,
+        checksum,
+        headers(Some("\"1\""), "5")
+    ));
+    assert!(unchanged(
+        "d",
+        sha256,
+        checksum,
+        modified("Mon, 01 Jan 2024 00:00:00 GMT")
+    ));
+    assert!(!unchanged(
+        "d",
+        sha256,
+        checksum,
+        modified("Tue, 02 Jan 2024 00:00:00 GMT")
+    ));
+
+    // the size of the download, not of the HEAD response
+    let mut etag_only = HeaderMap::new();
+    etag_only.insert(ETAG, "\"1\"".parse().unwrap());
+    validators.insert("e", &etag_only, &hashed);
+    let size = validators
+        .unchanged("e", sha256, checksum, &etag_only)
+        .unwrap()
+        .size;
+    assert_eq!(size, 11);
+}
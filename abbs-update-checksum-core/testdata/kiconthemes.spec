@@ -0,0 +1,10 @@
+VER=5.115.0
+REL=1
+# tarball and the translations, fetched separately
+SRCS="tbl::https://download.kde.org/stable/frameworks/${VER%.*}/kiconthemes-$VER.tar.xz \
+      git::commit=tags/v$VER::https://invent.kde.org/frameworks/kiconthemes-l10n"
+CHKSUMS="sha256::0000000000000000000000000000000000000000000000000000000000000000 \
+         SKIP"
+SRCS__LOONGSON3="tbl::https://example.org/kiconthemes-$VER-loongson3.tar.xz"
+CHKSUMS__LOONGSON3="sha256::1111111111111111111111111111111111111111111111111111111111111111"
+CHKUPDATE="anitya::id=8762"
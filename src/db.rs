@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use abbs_update_checksum_core::SourceChecksum;
+use eyre::{Result, WrapErr};
+use rusqlite::{params, Connection};
+
+/// SQLite database every run appends the checksums it computed to, for auditing
+/// how they evolve over time.
+pub struct Index {
+    conn: Connection,
+    /// Time of the run, shared by all of its rows.
+    timestamp: String,
+}
+
+impl Index {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path, timestamp: String) -> Result<Self> {
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("Failed to open database {}", path.display()))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS checksums (
+                package TEXT NOT NULL,
+                source TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                type TEXT NOT NULL,
+                arch TEXT,
+                checksum TEXT NOT NULL,
+                size INTEGER,
+                PRIMARY KEY (package, source, timestamp)
+            );",
+        )?;
+
+        Ok(Self { conn, timestamp })
+    }
+
+    /// Records the checksums of the sources of `package`.
+    pub fn record(&mut self, package: &str, sources: &[SourceChecksum]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        for src in sources {
+            tx.execute(
+                "INSERT INTO checksums (package, source, timestamp, type, arch, checksum, size)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (package, source, timestamp) DO UPDATE SET
+                    type = excluded.type,
+                    arch = excluded.arch,
+                    checksum = excluded.checksum,
+                    size = excluded.size",
+                params![
+                    package,
+                    src.url,
+                    self.timestamp,
+                    src.typ,
+                    src.arch,
+                    src.checksum,
+                    src.size,
+                ],
+            )?;
+        }
+
+        Ok(tx.commit()?)
+    }
+}
@@ -1,35 +1,1366 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs,
-    io::Write,
+    io::{self, IsTerminal, Read, Write},
     path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use abbs_update_checksum_core::get_new_spec;
-use clap::Parser;
+mod db;
+
+use abbs_update_checksum_core::{
+    apply_defines, format_checksums, format_sri, get_new_spec_with_options, list_sources,
+    update_from_sources, ChecksumAlgo, Event, GroupError, HostLimiter, IgnoreList, RateLimiter,
+    SkipStyle, Source, SourceChecksum, SourceStore, SourceValidators, UpdateChecksumError,
+    UpdateChecksumOptions, UpdateChecksumResult, DEFAULT_INDENT,
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use dashmap::DashMap;
-use eyre::{bail, OptionExt, Result};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use eyre::{bail, Result, WrapErr};
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{error, warn, LevelFilter};
+use serde::Serialize;
+use similar::TextDiff;
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 use walkdir::WalkDir;
 
+/// Nothing to do, everything is up to date.
+const EXIT_OK: u8 = 0;
+/// A runtime error, including invalid arguments.
+const EXIT_ERROR: u8 = 1;
+/// `check` found mismatches, or `update --dry-run` would change a spec.
+const EXIT_CHANGED: u8 = 2;
+/// Interrupted by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
 #[derive(Debug, Parser)]
+#[clap(
+    args_conflicts_with_subcommands = true,
+    after_help = "Exit status:\n  \
+        0    success, nothing to change\n  \
+        1    runtime error\n  \
+        2    check found mismatches, or dry-run would change a spec\n  \
+        130  interrupted"
+)]
 struct Args {
-    #[clap(short, long)]
-    dry_run: bool,
-    #[clap(short, long, default_value_t = String::from("."))]
+    #[clap(short, long, default_value_t = String::from("."), global = true)]
     tree: String,
-    #[clap(long, default_value_t = 4)]
+    #[clap(long, default_value_t = 4, global = true)]
     threads: usize,
+    /// Retry downloads failing with a connection error or a 408, 429 or 5xx status
+    /// this many times, with exponential backoff
+    #[clap(long, default_value_t = 3, global = true)]
+    retries: usize,
+    /// Keep partial downloads in temporary files to resume them on retry, when the
+    /// server supports range requests
+    #[clap(long, global = true)]
+    resume: bool,
+    /// Accept downloads whose size differs from their Content-Length, for servers
+    /// misreporting it
+    #[clap(long, global = true)]
+    ignore_content_length: bool,
+    /// Accept HTML pages for sources named like archives, which are otherwise taken
+    /// for a mirror's error page
+    #[clap(long, global = true)]
+    allow_html: bool,
+    /// Give up connecting, or on a whole request, after this many seconds, 0 for no
+    /// limit
+    #[clap(long, default_value_t = 0, global = true)]
+    timeout: u64,
+    /// Additional source types to treat as unhashable (SKIP), e.g. `darcs`
+    #[clap(long, global = true)]
+    skip_type: Vec<String>,
+    /// How to write CHKSUMS whose sources are all SKIP: `full`, `single` or `omit`
+    #[clap(long, default_value = "full", global = true)]
+    trim_trailing_skip: SkipStyle,
+    /// Compute checksums with this algorithm, `sha256`, `sha512` or `blake2b`,
+    /// instead of the one of the existing checksum, for sources without `algo=`
+    #[clap(long, global = true)]
+    algorithm: Option<ChecksumAlgo>,
+    /// Resolve pypi sources without `version=` to the latest release of their package
+    #[clap(long, global = true)]
+    pypi_latest: bool,
+    /// With --pypi-latest, also resolve to pre-releases and development releases
+    #[clap(long, global = true, requires = "pypi_latest")]
+    pypi_prerelease: bool,
+    /// Keep a single-line CHKSUMS on one line instead of writing one checksum per line
+    #[clap(long, global = true)]
+    keep_single_line: bool,
+    /// Indent the continuation lines of CHKSUMS without any yet by this many spaces
+    #[clap(long, default_value_t = DEFAULT_INDENT, global = true)]
+    indent: usize,
+    /// Write algorithm names in uppercase, e.g. `SHA256::`
+    #[clap(long, global = true)]
+    uppercase_algo: bool,
+    /// Strip the query of URLs, e.g. the signature of pre-signed URLs, from logs and
+    /// reports; URLs are fetched as is and SRCS is left alone
+    #[clap(long, global = true)]
+    strip_query: bool,
+    /// Fetch every source again from this base URL, e.g. an independent mirror, and
+    /// fail unless both downloads have the same checksum
+    #[clap(long, global = true)]
+    cross_check: Option<String>,
+    /// Send every request through this proxy, or, given as `unix:///path/to.sock`,
+    /// over a Unix domain socket
+    #[clap(long, global = true)]
+    proxy: Option<String>,
+    /// Limit downloads to this many bytes per second in total, across every
+    /// concurrent download
+    #[clap(long, global = true)]
+    max_rate: Option<u64>,
+    /// Maximum number of concurrent downloads from a single host, 0 for no limit
+    /// other than --threads
+    #[clap(long, default_value_t = 3, global = true)]
+    per_host: usize,
+    /// Keep downloaded sources in this directory, and read those already in it
+    /// instead of downloading them again
+    #[clap(long, global = true)]
+    cache_dir: Option<PathBuf>,
+    /// Fail for sources not in --cache-dir instead of downloading them
+    #[clap(
+        long,
+        global = true,
+        requires = "cache_dir",
+        conflicts_with_all = ["pypi_latest", "cross_check"]
+    )]
+    offline: bool,
+    /// OpenPGP keyring the signatures of sources given with `sig=` are verified
+    /// against
+    #[clap(long, global = true)]
+    keyring: Option<PathBuf>,
+    /// Also verify sources without `sig=` against the signature at their URL with
+    /// `.sig` or `.asc` appended, failing for those without one
+    #[clap(long, global = true, requires = "keyring")]
+    verify_signatures: bool,
+    /// Treat warnings as errors, see the README for which ones
+    #[clap(long, global = true)]
+    strict: bool,
+    /// Draw no progress bars and print no summary or notes. Progress bars are
+    /// also left out when stderr is not a terminal
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Log more, `-v` for info, `-vv` for debug, e.g. how specs are parsed and
+    /// rewritten, `-vvv` for trace; overrides the level of `RUST_LOG`
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Stop after this long, e.g. `2h`, cancelling in-flight downloads and reporting
+    /// the packages that were not processed
+    #[clap(long, value_parser = humantime::parse_duration, global = true)]
+    deadline: Option<Duration>,
+    #[clap(subcommand)]
+    command: Option<Cmd>,
+    /// Arguments of `update`, which runs when no subcommand is given
+    #[clap(flatten)]
+    update: UpdateArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Update the checksums of packages (default)
+    Update(UpdateArgs),
+    /// Verify the existing checksums without rewriting specs, exit non-zero on mismatch
+    Check(CheckArgs),
+    /// Print the checksums of URLs
+    Hash(HashArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct UpdateArgs {
+    /// Print a diff of the changes instead of writing the specs
+    #[clap(short, long)]
+    dry_run: bool,
+    /// Verify the existing checksums without writing anything, like `check`, and
+    /// exit with 2 if any differ
+    #[clap(
+        long,
+        conflicts_with_all = ["dry_run", "write", "patch", "after", "stdin", "force"]
+    )]
+    check: bool,
+    /// With --dry-run, print the whole updated specs instead of a diff
+    #[clap(long, requires = "dry_run")]
+    full: bool,
+    /// Record the uncompressed size of .tar.zst/.tar.xz sources
+    #[clap(long)]
+    record_uncompressed_size: bool,
+    /// Also write the size in bytes of each source into CHKSIZE, aligned with CHKSUMS
+    #[clap(long)]
+    write_sizes: bool,
+    /// Update the CHKSUMS groups that succeed and keep the old value of the failed ones
+    #[clap(long)]
+    partial: bool,
+    /// Record the tool version and update time in a comment above CHKSUMS
+    #[clap(long)]
+    stamp: bool,
+    /// Continue past sources that fail to download, keeping their old checksum, and
+    /// with the next package when one fails
+    #[clap(long)]
+    keep_going: bool,
+    /// Read sources from a spec, or as a JSON array of `{type, options, url, arch}` from stdin
+    #[clap(long, value_enum, default_value_t = Input::Spec)]
+    input: Input,
+    /// Read a spec from stdin and write it updated to stdout, ignoring --tree and
+    /// the packages
+    #[clap(long)]
+    stdin: bool,
+    /// Process every package in the tree
+    #[clap(long)]
+    all: bool,
+    /// Process up to this many packages at once, sharing the --threads downloads
+    /// between them
+    #[clap(long, default_value_t = 1)]
+    package_concurrency: usize,
+    /// Override a variable of the spec, e.g. `VER=1.2.3`, and print the resulting checksums
+    #[clap(long, value_parser = parse_define)]
+    define: Vec<(String, String)>,
+    /// With --define, write the overridden variables and new checksums into the spec
+    #[clap(long)]
+    write: bool,
+    /// Report sources of different packages or URLs sharing the same checksum
+    #[clap(long)]
+    dedupe_report: bool,
+    /// Write the changes of all specs into a single unified diff instead of the specs
+    #[clap(long)]
+    patch: Option<PathBuf>,
+    /// Command run through `sh -c` after each changed spec is written, `{spec}` is
+    /// replaced with the quoted spec path
+    #[clap(long)]
+    after: Option<String>,
+    /// Append the checksum of every downloaded source to this SQLite database,
+    /// created if missing
+    #[clap(long)]
+    db: Option<PathBuf>,
+    /// Record the ETag and Content-Length of downloaded sources in this JSON file,
+    /// created if missing, and skip downloading those whose checksum is still the
+    /// recorded one when a HEAD request shows them unchanged
+    #[clap(long)]
+    skip_unchanged: Option<PathBuf>,
+    /// Print the checksums computed for groups that failed, with `FIXME` in place of
+    /// the failed sources
+    #[clap(long, conflicts_with = "format")]
+    print_partial: bool,
+    /// Output format of the results, `json` prints an array with the old and new
+    /// checksums of every spec
+    #[clap(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Print each source as `<package> <key> <hash|skip|ignored|unexpandable> <type> <url>`
+    /// without downloading
+    #[clap(long)]
+    list_sources: bool,
+    /// Re-render the existing checksums with the chosen formatting without downloading
+    #[clap(long)]
+    normalize_only: bool,
+    /// Write specs even if they are unchanged
+    #[clap(long)]
+    force: bool,
+    /// Keep the original of each rewritten spec as `<spec>.bak`
+    #[clap(long)]
+    backup: bool,
+    /// Refuse to write a rewritten spec that no longer parses
+    #[clap(long)]
+    validate_output: bool,
+    /// Only update existing `CHKSUMS` variables, never add missing ones
+    #[clap(long)]
+    no_create: bool,
+    /// Only update the checksums used by this architecture, e.g. `amd64`: its
+    /// `CHKSUMS__<arch>`, or `CHKSUMS` if it has no `SRCS__<arch>`; repeatable
+    #[clap(long = "arch")]
+    archs: Vec<String>,
+    /// Reorder misaligned `CHKSUMS` to match `SRCS` without downloading when
+    /// possible, downloading only the groups that cannot be matched
+    #[clap(long)]
+    repair: bool,
+    /// Match the packages as regexes instead of glob patterns
+    #[clap(long)]
+    regex: bool,
+    /// Also process the packages listed in this file, one per line, ignoring blank
+    /// lines and `#` comments
+    #[clap(long)]
+    packages_from: Option<PathBuf>,
+    /// Packages to process, as glob patterns matched against their directory names,
+    /// e.g. `kde-*`
+    packages: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct CheckArgs {
+    /// Check every package in the tree
+    #[clap(long)]
+    all: bool,
+    /// Output format of the report
+    #[clap(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Report sources of different packages or URLs sharing the same checksum
+    #[clap(long)]
+    dedupe_report: bool,
+    /// Match the packages as regexes instead of glob patterns
+    #[clap(long)]
+    regex: bool,
+    /// Also process the packages listed in this file, one per line, ignoring blank
+    /// lines and `#` comments
+    #[clap(long)]
+    packages_from: Option<PathBuf>,
+    /// Packages to process, as glob patterns matched against their directory names,
+    /// e.g. `kde-*`
+    packages: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct HashArgs {
+    /// Print the checksums as Subresource Integrity digests, e.g.
+    /// `sha256-<base64>`, instead of `sha256::<hex>`
+    #[clap(long)]
+    sri: bool,
+    #[clap(required = true)]
+    urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Write,
+    DryRun,
+    Check,
+    /// Print the new checksums only.
+    Print,
+    /// Collect the changes into a patch.
+    Patch,
+}
+
+/// A spec before and after updating.
+struct SpecUpdate {
+    res: UpdateChecksumResult,
+    old: String,
+    new: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckReport {
+    package: String,
+    path: PathBuf,
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mismatches: Vec<Mismatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    Ok,
+    Mismatch,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+struct Mismatch {
+    key: String,
+    expected: Vec<String>,
+    actual: Vec<String>,
+}
+
+impl CheckReport {
+    fn new(spec: &Path, res: Result<UpdateChecksumResult>) -> Self {
+        let package = package_name(spec).to_string();
+        let path = spec.to_path_buf();
+
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                return Self {
+                    package,
+                    path,
+                    status: CheckStatus::Error,
+                    mismatches: vec![],
+                    error: Some(format!("{e:#}")),
+                }
+            }
+        };
+
+        let mut mismatches = res
+            .checksums
+            .into_iter()
+            .filter_map(|(key, actual)| {
+                let expected = res.old_checksums.get(&key).cloned().unwrap_or_default();
+                (expected != actual).then_some(Mismatch {
+                    key,
+                    expected,
+                    actual,
+                })
+            })
+            .collect::<Vec<_>>();
+        mismatches.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Self {
+            package,
+            path,
+            status: if mismatches.is_empty() {
+                CheckStatus::Ok
+            } else {
+                CheckStatus::Mismatch
+            },
+            mismatches,
+            error: None,
+        }
+    }
+}
+
+/// Outcome of updating a spec, printed with `--format json`.
+#[derive(Debug, Serialize)]
+struct UpdateReport {
     package: String,
+    path: PathBuf,
+    changed: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checksums: Vec<ChecksumChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChecksumChange {
+    key: String,
+    /// Architecture of `CHKSUMS__<arch>`, none for `CHKSUMS`.
+    arch: Option<String>,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+impl UpdateReport {
+    fn new(spec: &Path, res: Result<&UpdateChecksumResult, &eyre::Report>) -> Self {
+        let package = package_name(spec).to_string();
+        let path = spec.to_path_buf();
+
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                return Self {
+                    package,
+                    path,
+                    changed: false,
+                    checksums: vec![],
+                    error: Some(format!("{e:#}")),
+                }
+            }
+        };
+
+        let mut checksums = res
+            .checksums
+            .iter()
+            .map(|(key, new)| ChecksumChange {
+                key: key.clone(),
+                arch: key.strip_prefix("CHKSUMS__").map(|x| x.to_lowercase()),
+                old: res.old_checksums.get(key).cloned().unwrap_or_default(),
+                new: new.clone(),
+            })
+            .collect::<Vec<_>>();
+        checksums.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Self {
+            package,
+            path,
+            changed: res.changed(),
+            checksums,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Input {
+    Spec,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            e.print().ok();
+            // clap exits with 2 on usage errors, which means changes needed here
+            return if e.use_stderr() {
+                ExitCode::from(EXIT_ERROR)
+            } else {
+                ExitCode::from(EXIT_OK)
+            };
+        }
+    };
+
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    // per-module levels of RUST_LOG still apply on top
+    match args.verbose {
+        0 => &mut logger,
+        1 => logger.filter_level(LevelFilter::Info),
+        2 => logger.filter_level(LevelFilter::Debug),
+        _ => logger.filter_level(LevelFilter::Trace),
+    }
+    .init();
+
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run(args: Args) -> Result<ExitCode> {
+    let cancel = CancellationToken::new();
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || cancel.cancel())?;
+    }
+
+    // cancelled on deadline too, which is not an interruption
+    let download_cancel = cancel.child_token();
+    let deadline = args.deadline.map(|timeout| {
+        let token = download_cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            token.cancel();
+        });
+
+        Instant::now() + timeout
+    });
+
+    // shared by every spec, sized for `--threads` concurrent downloads
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(args.threads.max(1))
+        .enable_io()
+        .enable_time()
+        .build()?;
+
+    let opts = UpdateChecksumOptions {
+        threads: args.threads,
+        retries: args.retries,
+        resume: args.resume,
+        check_length: !args.ignore_content_length,
+        check_html: !args.allow_html,
+        timeout: (args.timeout > 0).then(|| Duration::from_secs(args.timeout)),
+        skip_types: args.skip_type,
+        cancel: Some(download_cancel),
+        skip_style: args.trim_trailing_skip,
+        uppercase_algo: args.uppercase_algo,
+        keep_single_line: args.keep_single_line,
+        indent: args.indent,
+        strip_query: args.strip_query,
+        cross_check: args.cross_check,
+        proxy: args.proxy,
+        max_rate: args.max_rate.map(RateLimiter::new),
+        per_host: (args.per_host > 0).then(|| HostLimiter::new(args.per_host)),
+        store: args.cache_dir.map(SourceStore::new),
+        offline: args.offline,
+        keyring: args.keyring,
+        verify_signatures: args.verify_signatures,
+        strict: args.strict,
+        algorithm: args.algorithm,
+        pypi_latest: args.pypi_latest,
+        pypi_prerelease: args.pypi_prerelease,
+        ..Default::default()
+    };
+
+    match args.command.unwrap_or(Cmd::Update(args.update)) {
+        Cmd::Update(update_args) => update(
+            update_args,
+            Path::new(&args.tree),
+            &rt,
+            opts,
+            &cancel,
+            deadline,
+            args.quiet,
+        ),
+        Cmd::Check(check_args) => check(
+            check_args,
+            Path::new(&args.tree),
+            &rt,
+            &opts,
+            &cancel,
+            deadline,
+            args.quiet,
+        ),
+        Cmd::Hash(hash_args) => hash(hash_args, &rt, &opts, &cancel),
+    }
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let args = Args::parse();
+fn update(
+    args: UpdateArgs,
+    tree: &Path,
+    rt: &Runtime,
+    opts: UpdateChecksumOptions,
+    cancel: &CancellationToken,
+    deadline: Option<Instant>,
+    quiet: bool,
+) -> Result<ExitCode> {
+    let opts = UpdateChecksumOptions {
+        record_uncompressed_size: args.record_uncompressed_size,
+        write_sizes: args.write_sizes,
+        partial: args.partial,
+        keep_going: args.keep_going,
+        stamp: args.stamp.then(|| {
+            format!(
+                "abbs-update-checksum v{} on {}",
+                env!("CARGO_PKG_VERSION"),
+                humantime::format_rfc3339_seconds(SystemTime::now())
+            )
+        }),
+        defines: args.define,
+        validate_output: args.validate_output,
+        normalize_only: args.normalize_only,
+        no_create: args.no_create,
+        archs: args.archs,
+        repair: args.repair,
+        validators: args
+            .skip_unchanged
+            .as_deref()
+            .map(SourceValidators::load)
+            .transpose()?,
+        ..opts
+    };
+
+    if args.input == Input::Json {
+        let res = update_json(rt, &opts);
+        if cancel.is_cancelled() {
+            exit_interrupted(&MultiProgress::new());
+        }
+
+        return res.map(|_| ExitCode::from(EXIT_OK));
+    }
+
+    if args.stdin {
+        let res = update_stdin(rt, &opts, quiet);
+        if cancel.is_cancelled() {
+            exit_interrupted(&MultiProgress::new());
+        }
+
+        return res.map(|_| ExitCode::from(EXIT_OK));
+    }
+
+    if args.check {
+        let check_args = CheckArgs {
+            all: args.all,
+            format: args.format,
+            dedupe_report: args.dedupe_report,
+            regex: args.regex,
+            packages_from: args.packages_from,
+            packages: args.packages,
+        };
+
+        return check(check_args, tree, rt, &opts, cancel, deadline, quiet);
+    }
+
+    let packages = with_listed_packages(args.packages, args.packages_from.as_deref())?;
+    if packages.is_empty() && !args.all {
+        bail!("No package specified");
+    }
+
+    let tree = get_tree(tree)?;
+    let specs = find_specs(&tree, &packages, args.all, args.regex)?;
+    let files = source_files(&specs)?;
+    let ignore = load_ignore_list(&tree)?;
+
+    if args.list_sources {
+        for file in &files {
+            print_sources(file, &opts_for(&opts, &ignore, file)?)?;
+        }
+
+        return Ok(ExitCode::from(EXIT_OK));
+    }
+
+    let mb = multi_progress(quiet);
+    let mut failed = 0;
+    let mut sources = vec![];
+
+    let mode = if args.dry_run {
+        Mode::DryRun
+    } else if args.patch.is_some() {
+        Mode::Patch
+    } else if !opts.defines.is_empty() && !args.write {
+        Mode::Print
+    } else {
+        Mode::Write
+    };
+
+    let mut patch = String::new();
+    let mut changed = false;
+    let mut reports = vec![];
+    let mut unprocessed = vec![];
+    let mut index = args
+        .db
+        .as_deref()
+        .map(|path| {
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            db::Index::open(path, timestamp)
+        })
+        .transpose()?;
+
+    if args.all && mode == Mode::Write && !quiet {
+        warn!(
+            "Updating all {} spec(s) of the tree, which downloads every non-VCS source, \
+             consider --dry-run first",
+            files.len()
+        );
+    }
+
+    let total = files.len();
+    let mut changed_specs = 0;
+    let bar = specs_bar(&mb, total);
+
+    // split so that at most max(--threads, --package-concurrency) downloads run
+    let concurrency = args.package_concurrency.max(1);
+    let opts = UpdateChecksumOptions {
+        threads: (opts.threads / concurrency).max(1),
+        ..opts
+    };
+    // kept for the next run even if this one fails
+    let save_validators = || match (&args.skip_unchanged, &opts.validators) {
+        (Some(path), Some(validators)) => validators.save(path),
+        _ => Ok(()),
+    };
 
-    let pkg = args.package;
-    let tree = get_tree(Path::new(&args.tree))?;
+    // only the downloads overlap, specs are written and reported one at a time, in
+    // the order they complete
+    let mut updates = futures::stream::iter(files.into_iter().map(|spec| {
+        let (opts, mb, bar, tree) = (&opts, &mb, &bar, &tree);
+        let ignore = &ignore;
+        async move {
+            if deadline_exceeded(deadline) {
+                return (spec, None);
+            }
 
-    let mut spec = None;
+            bar.set_message(
+                spec.strip_prefix(tree)
+                    .unwrap_or(&spec)
+                    .display()
+                    .to_string(),
+            );
+            let label = if concurrency > 1 {
+                package_name(&spec)
+            } else {
+                ""
+            };
+            let res = match opts_for(opts, ignore, &spec) {
+                Ok(opts) => compute_update(&spec, mb, &opts, mode, label).await,
+                Err(e) => Err(e),
+            };
+
+            (spec, Some(res))
+        }
+    }))
+    .buffer_unordered(concurrency);
+
+    while let Some((spec, res)) = rt.block_on(updates.next()) {
+        let Some(res) = res else {
+            unprocessed.push(spec);
+            continue;
+        };
+
+        let res = res.and_then(|update| {
+            apply_update(
+                &spec,
+                &update,
+                mode,
+                args.format,
+                args.force,
+                args.backup,
+                quiet,
+            )?;
+            Ok(update)
+        });
+        bar.inc(1);
+        if cancel.is_cancelled() {
+            exit_interrupted(&mb);
+        }
+
+        if res.is_err() && deadline_exceeded(deadline) {
+            unprocessed.push(spec);
+            continue;
+        }
+
+        let res = res.and_then(|update| {
+            if mode == Mode::Patch {
+                patch.push_str(&unified_diff(&tree, &spec, &update.old, &update.new));
+            }
+
+            if mode == Mode::DryRun && args.format == Format::Text {
+                if args.full {
+                    println!("{}", update.new);
+                } else {
+                    print!("{}", unified_diff(&tree, &spec, &update.old, &update.new));
+                }
+            }
+
+            collect_sources(&mut sources, &spec, &update.res);
+            changed |= update.res.changed();
+            changed_specs += update.res.changed() as usize;
+            if args.format == Format::Json {
+                reports.push(UpdateReport::new(&spec, Ok(&update.res)));
+            }
+
+            if let Some(index) = &mut index {
+                index.record(package_name(&spec), &update.res.sources)?;
+            }
+
+            if args.print_partial {
+                for (_, e) in &update.res.failed_groups {
+                    print_partial(&spec, e);
+                }
+            }
+
+            match &args.after {
+                Some(cmd) if mode == Mode::Write && update.res.changed() => {
+                    run_after_hook(cmd, &spec)?
+                }
+                _ => {}
+            }
+
+            // written with their old checksums, but still a failure
+            if !update.res.failed_sources.is_empty() {
+                bail!(
+                    "{} source(s) failed to download, kept their old checksums",
+                    update.res.failed_sources.len()
+                );
+            }
+            // e.g. groups failing to resolve, kept as a whole
+            if args.keep_going && !update.res.failed_groups.is_empty() {
+                bail!(
+                    "{} group(s) failed to update, kept their old value",
+                    update.res.failed_groups.len()
+                );
+            }
+
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            if args.print_partial {
+                print_partial(&spec, &e);
+            }
+
+            if !args.keep_going {
+                save_validators()?;
+                return Err(e);
+            }
+
+            error!("{}: {e:?}", spec.display());
+            if args.format == Format::Json {
+                reports.push(UpdateReport::new(&spec, Err(&e)));
+            }
+            failed += 1;
+        }
+    }
+
+    bar.finish_and_clear();
+    save_validators()?;
+    if total > 1 && !quiet {
+        eprintln!("{changed_specs} of {total} spec(s) changed, {failed} failed");
+    }
+
+    if args.format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+
+    if args.dedupe_report {
+        // keep stdout valid JSON
+        if args.format == Format::Json {
+            eprint!("{}", dedupe_report(&sources));
+        } else {
+            print!("{}", dedupe_report(&sources));
+        }
+    }
+
+    if let Some(path) = &args.patch {
+        fs::write(path, patch).wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    report_unprocessed(&unprocessed)?;
+
+    if failed > 0 {
+        bail!("Failed to update {failed} package(s)");
+    }
+
+    if mode == Mode::DryRun && changed {
+        return Ok(ExitCode::from(EXIT_CHANGED));
+    }
+
+    Ok(ExitCode::from(EXIT_OK))
+}
+
+fn check(
+    args: CheckArgs,
+    tree: &Path,
+    rt: &Runtime,
+    opts: &UpdateChecksumOptions,
+    cancel: &CancellationToken,
+    deadline: Option<Instant>,
+    quiet: bool,
+) -> Result<ExitCode> {
+    let packages = with_listed_packages(args.packages, args.packages_from.as_deref())?;
+    if packages.is_empty() && !args.all {
+        bail!("No package specified");
+    }
+
+    let tree = get_tree(tree)?;
+    let specs = find_specs(&tree, &packages, args.all, args.regex)?;
+    let files = source_files(&specs)?;
+    let ignore = load_ignore_list(&tree)?;
+
+    let mb = multi_progress(quiet);
+    let mut errors = 0;
+    let mut mismatches = 0;
+    let mut sources = vec![];
+    let mut reports = vec![];
+    let mut unprocessed = vec![];
+    let bar = specs_bar(&mb, files.len());
+
+    for spec in files {
+        if deadline_exceeded(deadline) {
+            unprocessed.push(spec);
+            continue;
+        }
+
+        bar.set_message(
+            spec.strip_prefix(&tree)
+                .unwrap_or(&spec)
+                .display()
+                .to_string(),
+        );
+        let res = opts_for(opts, &ignore, &spec)
+            .and_then(|opts| update_spec(&spec, rt, &mb, &opts, Mode::Check, args.format, quiet));
+        bar.inc(1);
+        if cancel.is_cancelled() {
+            exit_interrupted(&mb);
+        }
+
+        if res.is_err() && deadline_exceeded(deadline) {
+            unprocessed.push(spec);
+            continue;
+        }
+
+        let res = res.map(|x| x.res);
+        if let Ok(res) = &res {
+            collect_sources(&mut sources, &spec, res);
+        }
+
+        let report = CheckReport::new(&spec, res);
+
+        if args.format == Format::Text {
+            print_check_report(&report);
+        }
+
+        match report.status {
+            CheckStatus::Ok => {}
+            CheckStatus::Mismatch => mismatches += 1,
+            CheckStatus::Error => errors += 1,
+        }
+
+        reports.push(report);
+    }
+
+    bar.finish_and_clear();
+
+    if args.format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+
+    if args.dedupe_report {
+        // keep stdout valid JSON
+        if args.format == Format::Json {
+            eprint!("{}", dedupe_report(&sources));
+        } else {
+            print!("{}", dedupe_report(&sources));
+        }
+    }
+
+    report_unprocessed(&unprocessed)?;
+
+    if errors > 0 {
+        bail!("Failed to check {errors} package(s)");
+    }
+
+    if mismatches > 0 {
+        eprintln!("{mismatches} package(s) failed the check");
+        return Ok(ExitCode::from(EXIT_CHANGED));
+    }
+
+    Ok(ExitCode::from(EXIT_OK))
+}
+
+fn hash(
+    args: HashArgs,
+    rt: &Runtime,
+    opts: &UpdateChecksumOptions,
+    cancel: &CancellationToken,
+) -> Result<ExitCode> {
+    let sources = args
+        .urls
+        .into_iter()
+        .map(|url| Source {
+            typ: "tbl".to_string(),
+            options: vec![],
+            url,
+            arch: None,
+        })
+        .collect::<Vec<_>>();
+
+    let res = rt.block_on(update_from_sources(&sources, |_| {}, opts));
+    if cancel.is_cancelled() {
+        exit_interrupted(&MultiProgress::new());
+    }
+
+    for src in res? {
+        let checksum = if !args.sri {
+            src.checksum
+        } else if let Some(sri) = format_sri(&src.checksum) {
+            sri
+        } else {
+            bail!("{} has no Subresource Integrity form", src.checksum);
+        };
+        println!("{checksum}  {}", src.url);
+    }
+
+    Ok(ExitCode::from(EXIT_OK))
+}
+
+/// Clears the progress bars and exits with the conventional SIGINT status.
+///
+/// Specs are only written after all their downloads complete, so none is left
+/// half-written.
+fn exit_interrupted(mb: &MultiProgress) -> ! {
+    mb.clear().ok();
+    eprintln!("Interrupted");
+    std::process::exit(EXIT_INTERRUPTED);
+}
+
+fn deadline_exceeded(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|x| Instant::now() >= x)
+}
+
+/// Lists the files skipped because the deadline was exceeded, and fails if any.
+fn report_unprocessed(unprocessed: &[PathBuf]) -> Result<()> {
+    if unprocessed.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Deadline exceeded, not processed:");
+    for spec in unprocessed {
+        eprintln!("  {}", spec.display());
+    }
+
+    bail!(
+        "Deadline exceeded, {} file(s) not processed",
+        unprocessed.len()
+    );
+}
+
+fn print_sources(file: &Path, opts: &UpdateChecksumOptions) -> Result<()> {
+    let s = fs::read_to_string(file)?;
+    let pkg = package_name(file);
+
+    for src in list_sources(&s, opts)? {
+        println!(
+            "{pkg}\t{}\t{}\t{}\t{}",
+            src.key, src.class, src.typ, src.url
+        );
+    }
+
+    Ok(())
+}
+
+fn update_json(rt: &Runtime, opts: &UpdateChecksumOptions) -> Result<()> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let sources: Vec<Source> = serde_json::from_str(&input)?;
+
+    let res = rt.block_on(update_from_sources(&sources, |_| {}, opts))?;
+
+    println!("{}", serde_json::to_string_pretty(&res)?);
+
+    Ok(())
+}
+
+/// Updates the spec read from stdin and writes it to stdout, leaving stderr to
+/// the progress bars.
+fn update_stdin(rt: &Runtime, opts: &UpdateChecksumOptions, quiet: bool) -> Result<()> {
+    let mut spec = String::new();
+    io::stdin().read_to_string(&mut spec)?;
+    let mut spec = apply_defines(&spec, &opts.defines);
+
+    let mb = multi_progress(quiet);
+    let res = with_progress(&mb, |cb| {
+        rt.block_on(get_new_spec_with_options(&mut spec, cb, opts))
+    })?;
+
+    for (key, e) in &res.failed_groups {
+        error!("<stdin>: failed to update {key}: {e:?}");
+    }
+
+    io::stdout().write_all(spec.as_bytes())?;
+
+    Ok(())
+}
+
+/// Prints what was computed of a group that failed, in `CHKSUMS` form.
+fn print_partial(spec: &Path, e: &eyre::Report) {
+    // as returned by the core, or kept in `failed_groups`
+    let e = match e.downcast_ref() {
+        Some(UpdateChecksumError::Group(e)) => e,
+        _ => match e.downcast_ref::<GroupError>() {
+            Some(e) => e,
+            None => return,
+        },
+    };
+
+    let checksums = e
+        .checksums
+        .iter()
+        .map(|x| x.clone().unwrap_or_else(|| "FIXME".to_string()))
+        .collect::<Vec<_>>();
+
+    println!("{}:", spec.display());
+    println!("{}", format_checksums(&e.key, &checksums));
+}
+
+fn collect_sources(
+    sources: &mut Vec<(String, SourceChecksum)>,
+    spec: &Path,
+    res: &UpdateChecksumResult,
+) {
+    let pkg = package_name(spec);
+    sources.extend(res.sources.iter().map(|x| (pkg.to_string(), x.clone())));
+}
+
+/// Lists the checksums shared by different sources or packages.
+fn dedupe_report(sources: &[(String, SourceChecksum)]) -> String {
+    let mut groups: BTreeMap<&str, BTreeSet<(&str, &str)>> = BTreeMap::new();
+    for (pkg, src) in sources {
+        groups
+            .entry(src.checksum.as_str())
+            .or_default()
+            .insert((pkg.as_str(), src.url.as_str()));
+    }
+
+    let mut report = String::new();
+    for (checksum, srcs) in groups {
+        if srcs.len() < 2 {
+            continue;
+        }
+
+        report.push_str(&format!("{checksum} is shared by:\n"));
+        for (pkg, url) in srcs {
+            report.push_str(&format!("  {pkg}: {url}\n"));
+        }
+    }
+
+    report
+}
+
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got `{s}`"))
+}
+
+fn run_after_hook(cmd: &str, spec: &Path) -> Result<()> {
+    let cmd = cmd.replace("{spec}", &shell_quote(&spec.to_string_lossy()));
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .status()
+        .wrap_err_with(|| format!("Failed to run `{cmd}`"))?;
+
+    if !status.success() {
+        bail!("`{cmd}` failed: {status}");
+    }
+
+    Ok(())
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn load_ignore_list(tree: &Path) -> Result<IgnoreList> {
+    let path = tree.join(".abbs-checksum-ignore");
+    if !path.is_file() {
+        return Ok(IgnoreList::default());
+    }
+
+    let s = fs::read_to_string(&path)?;
+
+    IgnoreList::parse(&s).wrap_err_with(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Returns the options of `file`, a package spec or another file of its package
+/// which inherits the variables of the spec.
+fn opts_for(
+    opts: &UpdateChecksumOptions,
+    ignore: &IgnoreList,
+    file: &Path,
+) -> Result<UpdateChecksumOptions> {
+    let mut opts = UpdateChecksumOptions {
+        ignore: ignore.for_package(package_name(file)),
+        base_dir: package_spec(file).and_then(|x| x.parent().map(|x| x.to_path_buf())),
+        ..opts.clone()
+    };
+
+    if let Some(spec) = package_spec(file).filter(|x| x != file) {
+        let spec = fs::read_to_string(spec)?;
+        // overridden variables belong to the spec
+        opts.inherit = Some(apply_defines(&spec, &opts.defines));
+        opts.defines.clear();
+    }
+
+    Ok(opts)
+}
+
+fn print_check_report(report: &CheckReport) {
+    match report.status {
+        CheckStatus::Ok => println!("{}: OK", report.package),
+        CheckStatus::Mismatch => {
+            for m in &report.mismatches {
+                println!(
+                    "{}: {} mismatch\n  expected: {}\n  actual:   {}",
+                    report.package,
+                    m.key,
+                    m.expected.join(" "),
+                    m.actual.join(" ")
+                );
+            }
+        }
+        CheckStatus::Error => println!(
+            "{}: ERROR {}",
+            report.package,
+            report.error.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+/// Returns the `spec` of the package `file` belongs to.
+fn package_spec(file: &Path) -> Option<PathBuf> {
+    file.ancestors()
+        .skip(1)
+        .map(|x| x.join("spec"))
+        .find(|x| x.is_file())
+}
+
+fn package_name(file: &Path) -> &str {
+    file.ancestors()
+        .skip(1)
+        .find(|x| x.join("spec").is_file())
+        .or(file.parent())
+        .and_then(|x| x.file_name())
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+}
+
+/// Returns each spec followed by the other files of its package defining `SRCS`,
+/// i.e. `autobuild/defines` and the `defines` of subpackages.
+fn source_files(specs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+
+    for spec in specs {
+        files.push(spec.clone());
+
+        let Some(dir) = spec.parent() else {
+            continue;
+        };
+
+        let mut defines = vec![];
+        for i in WalkDir::new(dir).min_depth(2).max_depth(3) {
+            let i = i?;
+            if !i.file_type().is_file() || i.file_name() != "defines" {
+                continue;
+            }
+
+            let s = fs::read_to_string(i.path())?;
+            if s.lines().any(|x| x.starts_with("SRCS")) {
+                defines.push(i.into_path());
+            }
+        }
+
+        defines.sort();
+        files.extend(defines);
+    }
+
+    Ok(files)
+}
+
+/// Pattern matched against the package directory names.
+enum PackagePattern {
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+impl PackagePattern {
+    fn new(pattern: &str, regex: bool) -> Result<Self> {
+        Ok(if regex {
+            // the whole name has to match, as with globs
+            Self::Regex(
+                regex::Regex::new(&format!("^(?:{pattern})$"))
+                    .wrap_err_with(|| format!("Illegal regex {pattern}"))?,
+            )
+        } else {
+            Self::Glob(
+                glob::Pattern::new(pattern)
+                    .wrap_err_with(|| format!("Illegal glob pattern {pattern}"))?,
+            )
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(x) => x.matches(name),
+            Self::Regex(x) => x.is_match(name),
+        }
+    }
+}
+
+/// Returns `packages` followed by those listed in `path`, one per line.
+fn with_listed_packages(mut packages: Vec<String>, path: Option<&Path>) -> Result<Vec<String>> {
+    let Some(path) = path else {
+        return Ok(packages);
+    };
+
+    let list = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read the package list {}", path.display()))?;
+    let listed = list
+        .lines()
+        .map(|x| x.split_once('#').map_or(x, |(x, _)| x).trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    if listed.is_empty() {
+        bail!("The package list {} is empty", path.display());
+    }
+    packages.extend(listed);
+
+    Ok(packages)
+}
+
+/// Returns the specs of the packages matching the glob patterns, or regexes with
+/// `regex`, of `pkgs`, or of every package with `all`.
+fn find_specs(tree: &Path, pkgs: &[String], all: bool, regex: bool) -> Result<Vec<PathBuf>> {
+    let mut specs = vec![];
+    let patterns = pkgs
+        .iter()
+        .map(|x| PackagePattern::new(x, regex))
+        .collect::<Result<Vec<_>>>()?;
 
     for i in WalkDir::new(tree).max_depth(2).min_depth(2) {
         let i = i?;
@@ -39,55 +1370,412 @@ fn main() -> Result<()> {
 
         let path = i.path();
 
-        if !path.file_name().map(|x| x == &*pkg).unwrap_or(false) {
+        if all {
+            if path.join("spec").is_file() {
+                specs.push(path.join("spec"));
+            }
+
             continue;
         }
 
-        spec = Some(path.join("spec"));
-        break;
+        if !path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| patterns.iter().any(|pattern| pattern.matches(x)))
+            .unwrap_or(false)
+        {
+            continue;
+        }
+
+        specs.push(path.join("spec"));
     }
 
-    let spec = spec.ok_or_eyre("Failed to get spec")?;
+    specs.sort();
 
-    let mut spec_inner = fs::read_to_string(&spec)?;
+    if all {
+        return Ok(specs);
+    }
 
-    let mb = MultiProgress::new();
-    let map: DashMap<usize, ProgressBar> = DashMap::new();
+    for (pkg, pattern) in pkgs.iter().zip(&patterns) {
+        if !specs.iter().any(|x| pattern.matches(package_name(x))) {
+            bail!("No package matches {pkg}");
+        }
+    }
 
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_io()
-        .enable_time()
-        .build()?
-        .block_on(get_new_spec(
-            &mut spec_inner,
-            |status, index, inc, total| match map.get(&index) {
+    Ok(specs)
+}
+
+fn update_spec(
+    spec: &Path,
+    rt: &Runtime,
+    mb: &MultiProgress,
+    opts: &UpdateChecksumOptions,
+    mode: Mode,
+    format: Format,
+    quiet: bool,
+) -> Result<SpecUpdate> {
+    let update = rt.block_on(compute_update(spec, mb, opts, mode, ""))?;
+    apply_update(spec, &update, mode, format, false, false, quiet)?;
+
+    Ok(update)
+}
+
+/// Downloads the sources of `spec` and computes its new content, without writing
+/// it, drawing the progress on `mb` under `label`.
+async fn compute_update(
+    spec: &Path,
+    mb: &MultiProgress,
+    opts: &UpdateChecksumOptions,
+    mode: Mode,
+    label: &str,
+) -> Result<SpecUpdate> {
+    let old = fs::read_to_string(spec)?;
+    let mut new = old.clone();
+
+    if mode == Mode::Write || mode == Mode::DryRun || mode == Mode::Patch {
+        new = apply_defines(&new, &opts.defines);
+    }
+
+    let res = if mb.is_hidden() {
+        get_new_spec_with_options(&mut new, |_| {}, opts).await
+    } else {
+        let progress = Progress::new(mb, label);
+        let res =
+            get_new_spec_with_options(&mut new, |event: Event<'_>| progress.event(event), opts)
+                .await;
+        progress.finish();
+        res
+    };
+
+    Ok(SpecUpdate {
+        res: res?,
+        old,
+        new,
+    })
+}
+
+/// Reports the outcome of `update` and writes or prints the spec as `mode` says,
+/// printing no notes when `quiet`.
+fn apply_update(
+    spec: &Path,
+    update: &SpecUpdate,
+    mode: Mode,
+    format: Format,
+    force: bool,
+    backup: bool,
+    quiet: bool,
+) -> Result<()> {
+    let SpecUpdate {
+        res,
+        old,
+        new: spec_inner,
+    } = update;
+
+    for (key, e) in &res.failed_groups {
+        error!("{}: failed to update {key}: {e:?}", spec.display());
+    }
+
+    for (url, e) in &res.failed_sources {
+        error!("{}: failed to download {url}: {e:?}", spec.display());
+    }
+
+    // keep stdout valid JSON
+    let note = |msg: String| match format {
+        _ if quiet => {}
+        Format::Text => println!("{msg}"),
+        Format::Json => eprintln!("{msg}"),
+    };
+
+    for key in &res.mismatched {
+        warn!(
+            "{}: {key} did not have one entry per source, check the regenerated one",
+            spec.display()
+        );
+    }
+
+    for key in &res.repaired {
+        note(format!(
+            "{}: reordered {key} to match its SRCS",
+            spec.display()
+        ));
+    }
+
+    for (url, size) in &res.uncompressed_sizes {
+        note(format!("{url}: {size} bytes uncompressed"));
+    }
+
+    match mode {
+        Mode::Write if spec_inner == old && !force => {}
+        Mode::Write => write_atomically(spec, spec_inner, backup).wrap_err_with(|| {
+            format!("Failed to write {}, is the tree read-only?", spec.display())
+        })?,
+        // printed by the caller, which knows the tree root
+        Mode::DryRun | Mode::Check | Mode::Patch => {}
+        Mode::Print if format == Format::Json => {}
+        Mode::Print => {
+            let mut keys = res
+                .checksums
+                .iter()
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>();
+            keys.sort();
+
+            println!("{}:", spec.display());
+            for k in keys {
+                println!("{}", format_checksums(k, &res.checksums[k]));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces `path` with `content` through a temporary file renamed over it, so
+/// that an interruption leaves either the old or the new content, keeping the old
+/// one as `<path>.bak` if `backup`.
+fn write_atomically(path: &Path, content: &str, backup: bool) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(content.as_bytes())?;
+    if let Ok(metadata) = fs::metadata(path) {
+        tmp.as_file().set_permissions(metadata.permissions())?;
+    }
+    tmp.as_file().sync_all()?;
+
+    if backup {
+        let mut bak = path.as_os_str().to_owned();
+        bak.push(".bak");
+        fs::copy(path, &bak)?;
+    }
+    tmp.persist(path)?;
+
+    Ok(())
+}
+
+/// Adds a bar counting the specs processed out of `total`, hidden for a single
+/// spec.
+fn specs_bar(mb: &MultiProgress, total: usize) -> ProgressBar {
+    if total <= 1 {
+        return ProgressBar::hidden();
+    }
+
+    let pb = mb.add(ProgressBar::new(total as u64));
+    pb.set_style(ProgressStyle::with_template("[{pos}/{len}] {wide_msg}").unwrap());
+
+    pb
+}
+
+/// Draws nowhere when `quiet`, or when stderr is not a terminal, e.g. in CI logs.
+fn multi_progress(quiet: bool) -> MultiProgress {
+    if quiet || !io::stderr().is_terminal() {
+        return MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    MultiProgress::new()
+}
+
+/// Runs `f` with a callback drawing the progress of its downloads on `mb`.
+fn with_progress<T>(mb: &MultiProgress, f: impl FnOnce(&dyn Fn(Event<'_>)) -> T) -> T {
+    if mb.is_hidden() {
+        return f(&|_| {});
+    }
+
+    let progress = Progress::new(mb, "");
+    let res = f(&|event| progress.event(event));
+    progress.finish();
+
+    res
+}
+
+/// Progress of the downloads of a spec, drawn on a [`MultiProgress`].
+struct Progress<'a> {
+    mb: &'a MultiProgress,
+    bars: DashMap<usize, ProgressBar>,
+    phase: ProgressBar,
+    counter: PhaseCounter,
+}
+
+impl<'a> Progress<'a> {
+    /// Adds the spinner of the phase counts, prefixed with `label` unless empty,
+    /// e.g. the package name when several are processed at once.
+    fn new(mb: &'a MultiProgress, label: &str) -> Self {
+        let phase = mb.add(ProgressBar::new_spinner());
+        phase.set_style(ProgressStyle::with_template("{spinner:.green} {prefix}{msg}").unwrap());
+        if !label.is_empty() {
+            phase.set_prefix(format!("{label}: "));
+        }
+
+        Self {
+            mb,
+            bars: DashMap::new(),
+            phase,
+            counter: PhaseCounter::default(),
+        }
+    }
+
+    fn event(&self, event: Event<'_>) {
+        match event {
+            Event::ResolveStart { total } => {
+                self.counter
+                    .resolve_total
+                    .fetch_add(total, Ordering::Relaxed);
+            }
+            Event::Resolved => {
+                self.counter.resolved.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::DownloadQueued => {
+                self.counter.download_total.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::Downloading {
+                index,
+                url,
+                inc,
+                total,
+            } => match self.bars.get(&index) {
                 Some(pb) => {
-                    if !status {
-                        pb.inc(inc as u64);
-                    } else {
-                        pb.finish_and_clear();
-                    }
+                    pb.inc(inc as u64);
+                    self.counter.hashed(inc);
                 }
                 None => {
-                    let pb = mb.add(ProgressBar::new(total));
-                    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-                        .unwrap()
-                        .progress_chars("#>-"));
+                    self.counter.started(total);
+                    self.counter.hashed(inc);
+                    let pb = if total == 0 {
+                        // no ratio nor ETA to show without a size
+                        let pb = self.mb.add(ProgressBar::new_spinner());
+                        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] {bytes} ({bytes_per_sec})").unwrap());
+                        pb
+                    } else {
+                        let pb = self.mb.add(ProgressBar::new(total));
+                        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+                            .unwrap()
+                            .progress_chars("#>-"));
+                        pb
+                    };
+                    // the file name, the full URL rarely fits
+                    pb.set_message(url.rsplit('/').next().unwrap_or(url).to_string());
                     pb.inc(inc as u64);
-                    map.insert(index, pb);
+                    self.bars.insert(index, pb);
                 }
             },
-            args.threads,
-        ))?;
+            Event::Downloaded { index, .. } => {
+                match self.bars.get(&index) {
+                    Some(pb) => {
+                        // removed so that bars do not pile up across specs
+                        pb.finish_and_clear();
+                        self.mb.remove(&pb);
+                    }
+                    None => {
+                        self.counter.reused.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                self.counter.downloaded.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.phase.set_message(self.counter.to_string());
+        self.phase.tick();
+    }
 
-    if args.dry_run {
-        println!("{}", spec_inner);
-    } else {
-        let mut f = fs::File::create(&spec)?;
-        f.write_all(spec_inner.as_bytes())?;
+    fn finish(self) {
+        self.phase.finish_and_clear();
+        self.mb.remove(&self.phase);
     }
+}
 
-    Ok(())
+/// Returns a `patch -p1` compatible diff of `spec`, relative to the tree root.
+fn unified_diff(tree: &Path, spec: &Path, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+
+    let rel = spec
+        .strip_prefix(tree)
+        .unwrap_or(spec)
+        .display()
+        .to_string();
+    let a = format!("a/{rel}");
+    let b = format!("b/{rel}");
+    let diff = TextDiff::from_lines(old, new);
+
+    diff.unified_diff().header(&a, &b).to_string()
+}
+
+/// Aggregated counts of the resolution and download phases.
+#[derive(Default)]
+struct PhaseCounter {
+    resolved: AtomicUsize,
+    resolve_total: AtomicUsize,
+    downloaded: AtomicUsize,
+    download_total: AtomicUsize,
+    /// Downloads finished without transferring anything, e.g. served from the cache.
+    reused: AtomicUsize,
+    /// Downloads started with a known `Content-Length`, and the sum of those.
+    sized: AtomicUsize,
+    sized_bytes: AtomicU64,
+    /// Bytes hashed so far, since the first download started.
+    hashed: AtomicU64,
+    first_download: OnceLock<Instant>,
+}
+
+impl PhaseCounter {
+    fn started(&self, total: u64) {
+        self.first_download.get_or_init(Instant::now);
+        if total > 0 {
+            self.sized.fetch_add(1, Ordering::Relaxed);
+            self.sized_bytes.fetch_add(total, Ordering::Relaxed);
+        }
+    }
+
+    fn hashed(&self, inc: usize) {
+        self.hashed.fetch_add(inc as u64, Ordering::Relaxed);
+    }
+
+    /// Estimates the time left for every queued download of the spec from the
+    /// throughput so far.
+    ///
+    /// This is an extrapolation: sizes are only known from the `Content-Length` of
+    /// the downloads started so far, no `HEAD` request is sent ahead for the
+    /// others, which are assumed to be as large as the average known one.
+    fn eta(&self) -> Option<Duration> {
+        let elapsed = self.first_download.get()?.elapsed().as_secs_f64();
+        let hashed = self.hashed.load(Ordering::Relaxed);
+        let sized = self.sized.load(Ordering::Relaxed) as u64;
+        let sized_bytes = self.sized_bytes.load(Ordering::Relaxed);
+        if sized == 0 || hashed == 0 || elapsed < 1.0 {
+            return None;
+        }
+
+        let unknown = (self.download_total.load(Ordering::Relaxed) as u64)
+            .saturating_sub(self.reused.load(Ordering::Relaxed) as u64)
+            .saturating_sub(sized);
+        let total = sized_bytes + unknown * (sized_bytes / sized);
+        let throughput = hashed as f64 / elapsed;
+
+        Some(Duration::from_secs(
+            (total.saturating_sub(hashed) as f64 / throughput) as u64,
+        ))
+    }
+}
+
+impl std::fmt::Display for PhaseCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "resolving {}/{}, downloading {}/{}",
+            self.resolved.load(Ordering::Relaxed),
+            self.resolve_total.load(Ordering::Relaxed),
+            self.downloaded.load(Ordering::Relaxed),
+            self.download_total.load(Ordering::Relaxed),
+        )?;
+
+        match self.eta() {
+            Some(eta) => write!(f, ", about {} left", humantime::format_duration(eta)),
+            None => Ok(()),
+        }
+    }
 }
 
 fn get_tree(directory: &Path) -> Result<PathBuf> {
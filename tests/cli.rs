@@ -0,0 +1,201 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use common::spec;
+use common::status;
+use common::tree;
+use common::BIN;
+
+#[test]
+fn test_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(BIN)
+        .args(["update", "--stdin", "-t", "/nonexistent"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(spec("sha256::abc").as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), spec("SKIP"));
+}
+
+#[test]
+fn test_update_json() {
+    let tree = tree(&spec("sha256::abc"));
+
+    let output = Command::new(BIN)
+        .args(["update", "--format", "json", "--dry-run", "-t"])
+        .arg(tree.path())
+        .arg("foo")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let reports: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(reports[0]["package"], "foo");
+    assert_eq!(reports[0]["changed"], true);
+    assert_eq!(reports[0]["checksums"][0]["key"], "CHKSUMS");
+    assert_eq!(reports[0]["checksums"][0]["old"][0], "sha256::abc");
+    assert_eq!(reports[0]["checksums"][0]["new"][0], "SKIP");
+}
+
+#[test]
+fn test_dry_run_diff() {
+    let tree = tree(&spec("sha256::abc"));
+
+    let output = Command::new(BIN)
+        .args(["update", "--dry-run", "-t"])
+        .arg(tree.path())
+        .arg("foo")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("--- a/app-utils/foo/spec\n"));
+    assert!(stdout.contains("\n-CHKSUMS=\"sha256::abc\"\n+CHKSUMS=\"SKIP\"\n"));
+    assert!(!stdout.contains("\nSRCS="));
+
+    let output = Command::new(BIN)
+        .args(["update", "--dry-run", "--full", "-t"])
+        .arg(tree.path())
+        .arg("foo")
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("SRCS="));
+}
+
+#[test]
+fn test_package_patterns() {
+    let tree = tree(&spec("SKIP"));
+    let tree = tree.path().to_str().unwrap();
+
+    assert_eq!(status(&["check", "-t", tree, "f*"]), Some(0));
+    assert_eq!(status(&["check", "-t", tree, "f*", "bar-*"]), Some(1));
+    assert_eq!(status(&["check", "-t", tree, "--regex", "fo+"]), Some(0));
+    assert_eq!(status(&["check", "-t", tree, "--regex", "f"]), Some(1));
+}
+
+#[test]
+fn test_packages_from() {
+    let tree = tree(&spec("SKIP"));
+    let list = tree.path().join("list.txt");
+    let from = |list: &Path| {
+        status(&[
+            "check",
+            "-t",
+            tree.path().to_str().unwrap(),
+            "--packages-from",
+            list.to_str().unwrap(),
+        ])
+    };
+
+    fs::write(&list, "# curated\n\nfoo # the only one\n").unwrap();
+    assert_eq!(from(&list), Some(0));
+
+    fs::write(&list, "# nothing\n\n").unwrap();
+    assert_eq!(from(&list), Some(1));
+    assert_eq!(from(Path::new("/nonexistent")), Some(1));
+}
+
+#[test]
+fn test_package_concurrency() {
+    let tree = tree(&spec("sha256::abc"));
+    fs::create_dir_all(tree.path().join("app-utils/bar")).unwrap();
+    fs::write(
+        tree.path().join("app-utils/bar/spec"),
+        "SRCS=\"git::commit=tags/v1.0::https://example.org/bar.git\"\nCHKSUMS=\"sha256::abc\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(BIN)
+        .args(["update", "--dry-run", "--package-concurrency", "2", "-t"])
+        .arg(tree.path())
+        .args(["foo", "bar"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("--- a/app-utils/foo/spec\n"));
+    assert!(stdout.contains("--- a/app-utils/bar/spec\n"));
+}
+
+#[test]
+fn test_backup() {
+    let tree = tree(&spec("sha256::abc"));
+    let path = tree.path().join("app-utils/foo/spec");
+
+    assert_eq!(
+        status(&[
+            "update",
+            "--backup",
+            "-t",
+            tree.path().to_str().unwrap(),
+            "foo"
+        ]),
+        Some(0)
+    );
+    assert!(fs::read_to_string(&path)
+        .unwrap()
+        .contains("CHKSUMS=\"SKIP\""));
+    assert_eq!(
+        fs::read_to_string(tree.path().join("app-utils/foo/spec.bak")).unwrap(),
+        spec("sha256::abc")
+    );
+}
+
+#[test]
+fn test_quiet() {
+    let tree = tree(&spec("SKIP"));
+    fs::create_dir_all(tree.path().join("app-utils/bar")).unwrap();
+    fs::write(tree.path().join("app-utils/bar/spec"), spec("SKIP")).unwrap();
+
+    let output = Command::new(BIN)
+        .args(["update", "--quiet", "-t"])
+        .arg(tree.path())
+        .args(["foo", "bar"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+
+    // nor notes, e.g. of a repaired CHKSUMS, nothing is downloaded
+    let misaligned = r#"SRCS="git::commit=tags/v1.0::https://example.org/foo.git \
+      tbl::http://127.0.0.1:1/foo.tar.gz"
+CHKSUMS="sha256::abc SKIP"
+"#;
+    let update = |quiet: bool| {
+        fs::write(tree.path().join("app-utils/foo/spec"), misaligned).unwrap();
+        let mut cmd = Command::new(BIN);
+        cmd.args(["update", "--repair", "-t"])
+            .arg(tree.path())
+            .arg("foo");
+        if quiet {
+            cmd.arg("--quiet");
+        }
+
+        cmd.output().unwrap()
+    };
+
+    let output = update(false);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("reordered CHKSUMS"));
+    let output = update(true);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,28 @@
+use std::fs;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_abbs-update-checksum");
+
+/// `SRCS` of a package only built from git, checksummed as `SKIP`.
+pub const SRCS: &str = "SRCS=\"git::commit=tags/v1.0::https://example.org/foo.git\"\n";
+
+/// Returns a spec of [`SRCS`] with `chksums` as its `CHKSUMS`.
+pub fn spec(chksums: &str) -> String {
+    format!("{SRCS}CHKSUMS=\"{chksums}\"\n")
+}
+
+/// Creates a tree containing the package `foo` with `spec`, removed once dropped.
+pub fn tree(spec: &str) -> TempDir {
+    let tree = tempfile::tempdir().unwrap();
+    fs::create_dir_all(tree.path().join("groups")).unwrap();
+    fs::create_dir_all(tree.path().join("app-utils/foo")).unwrap();
+    fs::write(tree.path().join("app-utils/foo/spec"), spec).unwrap();
+
+    tree
+}
+
+pub fn status(args: &[&str]) -> Option<i32> {
+    Command::new(BIN).args(args).status().unwrap().code()
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use common::spec;
+use common::status;
+use common::tree;
+use common::BIN;
+
+#[test]
+fn test_exit_ok() {
+    let tree = tree(&spec("SKIP"));
+    let tree = tree.path().to_str().unwrap();
+
+    assert_eq!(status(&["check", "-t", tree, "foo"]), Some(0));
+}
+
+#[test]
+fn test_exit_error() {
+    let tree = tree(&spec("SKIP"));
+    let tree = tree.path().to_str().unwrap();
+
+    assert_eq!(status(&["check", "-t", tree, "bar"]), Some(1));
+    assert_eq!(status(&["--no-such-flag"]), Some(1));
+}
+
+#[test]
+fn test_exit_changed() {
+    let tree = tree(&spec("sha256::abc"));
+    let path = tree.path().to_str().unwrap();
+
+    assert_eq!(status(&["check", "-t", path, "foo"]), Some(2));
+    assert_eq!(status(&["update", "--dry-run", "-t", path, "foo"]), Some(2));
+    assert_eq!(status(&["--check", "-t", path, "foo"]), Some(2));
+    assert_eq!(
+        fs::read_to_string(tree.path().join("app-utils/foo/spec")).unwrap(),
+        spec("sha256::abc")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_exit_interrupted() {
+    use std::net::TcpListener;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // accepts connections but never responds
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tree = tree(&format!(
+        "SRCS=\"tbl::http://{addr}/foo.tar.gz\"\nCHKSUMS=\"sha256::abc\"\n"
+    ));
+
+    let mut child = Command::new(BIN)
+        .args(["update", "-t", tree.path().to_str().unwrap(), "foo"])
+        .spawn()
+        .unwrap();
+    let _conn = listener.accept().unwrap();
+    sleep(Duration::from_millis(200));
+
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+}